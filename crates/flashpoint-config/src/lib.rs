@@ -1,3 +1,4 @@
+pub mod template;
 pub mod types;
 
 #[cfg(test)]
@@ -0,0 +1,208 @@
+//! Expands the placeholders used by the legacy launcher in services.json, launch commands and app
+//! path overrides, e.g. `<fpPath>/Legacy/router.php`.
+
+use crate::types::{AppPathOverride, BackProcessInfo, Services};
+use std::path::Path;
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+  UnknownPlaceholder {
+    placeholder: String,
+    template: String,
+  },
+}
+
+impl std::fmt::Display for TemplateError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TemplateError::UnknownPlaceholder {
+        placeholder,
+        template,
+      } => write!(
+        f,
+        "unknown placeholder <{}> in \"{}\"",
+        placeholder, template
+      ),
+    }
+  }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The values placeholders expand to.
+#[derive(Clone, Debug)]
+pub struct TemplateVars {
+  /// `<fpPath>`, the Flashpoint folder.
+  pub fp_path: String,
+  /// `<os>`, named the way Node names platforms: `win32`, `darwin`, `linux`...
+  pub os: String,
+  /// `<cwd>`, the backend's working directory.
+  pub cwd: String,
+}
+
+impl TemplateVars {
+  /// Variables for the platform we're running on.
+  pub fn new(fp_path: &Path) -> Self {
+    let os = match std::env::consts::OS {
+      "windows" => "win32",
+      "macos" => "darwin",
+      os => os,
+    };
+    Self {
+      fp_path: fp_path.to_string_lossy().to_string(),
+      os: os.to_string(),
+      cwd: std::env::current_dir()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default(),
+    }
+  }
+
+  fn lookup(&self, placeholder: &str) -> Option<&str> {
+    match placeholder {
+      "fpPath" => Some(&self.fp_path),
+      "os" => Some(&self.os),
+      "cwd" => Some(&self.cwd),
+      _ => None,
+    }
+  }
+
+  /// Replaces every `<name>` in `template`. Only `<` followed by a name and `>` is a placeholder,
+  /// anything else (like `a <= b`) is left as it is.
+  pub fn expand(&self, template: &str) -> Result<String, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('<') {
+      out.push_str(&rest[..start]);
+      let after = &rest[start + 1..];
+      let name_len = after
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(after.len());
+      let name = &after[..name_len];
+      let is_placeholder =
+        after[name_len..].starts_with('>') && name.starts_with(|c: char| c.is_ascii_alphabetic());
+      if !is_placeholder {
+        out.push('<');
+        rest = after;
+        continue;
+      }
+      let value = self
+        .lookup(name)
+        .ok_or_else(|| TemplateError::UnknownPlaceholder {
+          placeholder: name.to_string(),
+          template: template.to_string(),
+        })?;
+      out.push_str(value);
+      rest = &after[name_len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+  }
+
+  /// The application path to launch with, after the first enabled override matching `app_path`.
+  pub fn resolve_app_path(
+    &self,
+    app_path: &str,
+    overrides: &[AppPathOverride],
+  ) -> Result<String, TemplateError> {
+    let app_path = self.expand(app_path)?;
+    for o in overrides.iter().filter(|o| o.enabled) {
+      if self.expand(&o.path)? == app_path {
+        return self.expand(&o.r#override);
+      }
+    }
+    Ok(app_path)
+  }
+}
+
+impl BackProcessInfo {
+  /// A copy with the placeholders in `path`, `filename` and `arguments` expanded.
+  pub fn expand(&self, vars: &TemplateVars) -> Result<Self, TemplateError> {
+    Ok(Self {
+      path: vars.expand(&self.path)?,
+      filename: vars.expand(&self.filename)?,
+      arguments: self
+        .arguments
+        .iter()
+        .map(|a| vars.expand(a))
+        .collect::<Result<_, _>>()?,
+      ..self.clone()
+    })
+  }
+}
+
+impl Services {
+  /// A copy with the placeholders of every process expanded.
+  pub fn expand(&self, vars: &TemplateVars) -> Result<Self, TemplateError> {
+    let expand_all = |infos: &[BackProcessInfo]| -> Result<Vec<_>, TemplateError> {
+      infos.iter().map(|i| i.expand(vars)).collect()
+    };
+    Ok(Self {
+      server: expand_all(&self.server)?,
+      daemon: expand_all(&self.daemon)?,
+      start: expand_all(&self.start)?,
+      stop: expand_all(&self.stop)?,
+      watch: self
+        .watch
+        .iter()
+        .map(|w| vars.expand(w))
+        .collect::<Result<_, _>>()?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn vars() -> TemplateVars {
+    TemplateVars {
+      fp_path: "/fp".to_string(),
+      os: "linux".to_string(),
+      cwd: "/home".to_string(),
+    }
+  }
+
+  #[test]
+  fn expands_placeholders() {
+    let vars = vars();
+    assert_eq!(
+      vars.expand("<fpPath>/Legacy/<os>").unwrap(),
+      "/fp/Legacy/linux"
+    );
+    assert_eq!(vars.expand("<cwd><cwd>").unwrap(), "/home/home");
+    // Not placeholders.
+    assert_eq!(vars.expand("a <= b <").unwrap(), "a <= b <");
+    assert_eq!(vars.expand("<1> <a b>").unwrap(), "<1> <a b>");
+    assert_eq!(
+      vars.expand("<fpPath>/<home>"),
+      Err(TemplateError::UnknownPlaceholder {
+        placeholder: "home".to_string(),
+        template: "<fpPath>/<home>".to_string(),
+      })
+    );
+  }
+
+  #[test]
+  fn resolves_app_path_overrides() {
+    let vars = vars();
+    let o = |path: &str, r#override: &str, enabled: bool| AppPathOverride {
+      path: path.to_string(),
+      r#override: r#override.to_string(),
+      enabled,
+    };
+    let overrides = vec![
+      o("FPSoftware/Flash.exe", "disabled", false),
+      o("FPSoftware/Flash.exe", "<fpPath>/flash-<os>", true),
+    ];
+    assert_eq!(
+      vars
+        .resolve_app_path("FPSoftware/Flash.exe", &overrides)
+        .unwrap(),
+      "/fp/flash-linux"
+    );
+    assert_eq!(
+      vars.resolve_app_path("<fpPath>/other", &overrides).unwrap(),
+      "/fp/other"
+    );
+  }
+}
@@ -5,15 +5,17 @@ use std::collections::HashMap;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BackProcessInfo {
   #[serde(default)]
-  name: String,
+  pub name: String,
   #[serde(default)]
-  mad4fp: bool,
-  path: String,
-  filename: String,
+  pub mad4fp: bool,
+  /// Working directory, relative to the Flashpoint folder.
+  pub path: String,
+  pub filename: String,
   #[serde(default)]
-  arguments: Vec<String>,
+  pub arguments: Vec<String>,
+  /// Whether the process is killed when the backend exits, rather than left running.
   #[serde(default)]
-  kill: bool,
+  pub kill: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -116,6 +118,12 @@ pub struct Config {
   pub gotd_url: String,
   #[serde(default)]
   pub gotd_show_all: bool,
+  /// Maximum number of database connections used for reads.
+  #[serde(default = "database_pool_size")]
+  pub database_pool_size: u32,
+  /// Milliseconds a database connection waits on a lock before giving up.
+  #[serde(default = "database_busy_timeout")]
+  pub database_busy_timeout: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -392,3 +400,11 @@ fn images_port_max() -> u16 {
 fn gotd_url() -> String {
   "https://download.unstable.life/gotd.json".to_string()
 }
+
+fn database_pool_size() -> u32 {
+  4
+}
+
+fn database_busy_timeout() -> u64 {
+  5000
+}
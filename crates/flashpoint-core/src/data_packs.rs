@@ -0,0 +1,328 @@
+//! Checks the data packs folder against the `game_data` table.
+//!
+//! A file belongs to a data pack if it's at the pack's recorded `path`, or failing that, if it's
+//! named like the legacy launcher names downloaded packs: `<gameId>-<dateAdded in ms>.zip`.
+//! Recorded paths are relative to the data packs folder and always use `/`.
+
+use flashpoint_database::game_data::{self, GameDataPresence};
+use flashpoint_database::models::GameData;
+use flashpoint_database::types::DbState;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// How often a progress event is sent, in files.
+const PROGRESS_INTERVAL: usize = 50;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DataPackScanProgress {
+  pub scanned: usize,
+  pub total: usize,
+  /// The file being scanned, relative to the data packs folder.
+  pub current: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MissingDataPack {
+  pub game_data_id: i32,
+  pub game_id: Option<String>,
+  pub path: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CorruptDataPack {
+  pub game_data_id: i32,
+  pub path: String,
+  pub reason: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DataPackScanReport {
+  /// Data packs found on disk, and verified if asked to.
+  pub present: usize,
+  /// Data packs with no file.
+  pub missing: Vec<MissingDataPack>,
+  /// Data packs whose file failed verification.
+  pub corrupt: Vec<CorruptDataPack>,
+  /// Files that don't belong to any data pack.
+  pub orphaned: Vec<String>,
+}
+
+/// Scans the data packs folder, then records which data packs are present in the database.
+pub fn scan_and_update(
+  db: &DbState,
+  folder: &Path,
+  verify: bool,
+  progress: impl FnMut(DataPackScanProgress),
+) -> Result<DataPackScanReport, Box<dyn std::error::Error>> {
+  let rows = game_data::find_all_game_data(db)?;
+  let (presence, report) = scan_data_packs(folder, &rows, verify, progress)?;
+  game_data::update_game_data_presence(db, presence)?;
+  Ok(report)
+}
+
+/// Matches every file in `folder` to a data pack, optionally checking its sha256 and crc32.
+/// Returns the on-disk status of every data pack whose status changed, along with the report.
+pub fn scan_data_packs(
+  folder: &Path,
+  rows: &[GameData],
+  verify: bool,
+  mut progress: impl FnMut(DataPackScanProgress),
+) -> io::Result<(Vec<GameDataPresence>, DataPackScanReport)> {
+  let mut files = vec![];
+  if folder.is_dir() {
+    collect_files(folder, &mut files)?;
+  }
+  files.sort();
+  let total = files.len();
+
+  let by_path: HashMap<&str, &GameData> = rows
+    .iter()
+    .filter_map(|r| Some((r.path.as_deref()?, r)))
+    .collect();
+  let by_name: HashMap<String, &GameData> = rows
+    .iter()
+    .filter_map(|r| Some((legacy_file_name(r)?, r)))
+    .collect();
+
+  let mut report = DataPackScanReport::default();
+  let mut found: HashMap<i32, GameDataPresence> = HashMap::new();
+  for (i, file) in files.iter().enumerate() {
+    let rel = relative_path(folder, file);
+    if i % PROGRESS_INTERVAL == 0 {
+      progress(DataPackScanProgress {
+        scanned: i,
+        total,
+        current: Some(rel.clone()),
+      });
+    }
+    let file_name = file
+      .file_name()
+      .map(|n| n.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let row = match by_path
+      .get(rel.as_str())
+      .or_else(|| by_name.get(&file_name))
+    {
+      Some(row) if !found.contains_key(&row.id) => row,
+      _ => {
+        report.orphaned.push(rel);
+        continue;
+      }
+    };
+
+    // A file that can't be read can't be verified either, it shouldn't stop the rest of the scan.
+    let problem = if verify {
+      verify_file(file, row).unwrap_or_else(|e| Some(e.to_string()))
+    } else {
+      None
+    };
+    if let Some(reason) = &problem {
+      report.corrupt.push(CorruptDataPack {
+        game_data_id: row.id,
+        path: rel.clone(),
+        reason: reason.clone(),
+      });
+    } else {
+      report.present += 1;
+    }
+    found.insert(
+      row.id,
+      GameDataPresence {
+        game_data_id: row.id,
+        present_on_disk: problem.is_none(),
+        path: Some(rel),
+      },
+    );
+  }
+  progress(DataPackScanProgress {
+    scanned: total,
+    total,
+    current: None,
+  });
+
+  let mut presence = vec![];
+  for row in rows {
+    let p = match found.remove(&row.id) {
+      Some(p) => p,
+      None => {
+        report.missing.push(MissingDataPack {
+          game_data_id: row.id,
+          game_id: row.game_id.clone(),
+          path: row.path.clone(),
+        });
+        // Keep the recorded path, the file may come back.
+        GameDataPresence {
+          game_data_id: row.id,
+          present_on_disk: false,
+          path: row.path.clone(),
+        }
+      }
+    };
+    if p.present_on_disk != row.present_on_disk || p.path != row.path {
+      presence.push(p);
+    }
+  }
+  Ok((presence, report))
+}
+
+fn legacy_file_name(row: &GameData) -> Option<String> {
+  Some(format!(
+    "{}-{}.zip",
+    row.game_id.as_ref()?,
+    row.date_added.timestamp_millis()
+  ))
+}
+
+fn relative_path(folder: &Path, file: &Path) -> String {
+  file
+    .strip_prefix(folder)
+    .unwrap_or(file)
+    .components()
+    .map(|c| c.as_os_str().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.is_dir() {
+      collect_files(&path, files)?;
+    } else {
+      files.push(path);
+    }
+  }
+  Ok(())
+}
+
+/// Returns why the file doesn't match the data pack, if it doesn't.
+fn verify_file(file: &Path, row: &GameData) -> io::Result<Option<String>> {
+  let mut reader = File::open(file)?;
+  let mut sha256 = Sha256::new();
+  let mut crc32 = crc32fast::Hasher::new();
+  let mut buf = vec![0; 64 * 1024];
+  loop {
+    let read = reader.read(&mut buf)?;
+    if read == 0 {
+      break;
+    }
+    sha256.update(&buf[..read]);
+    crc32.update(&buf[..read]);
+  }
+  let sha256 = format!("{:x}", sha256.finalize());
+  if !sha256.eq_ignore_ascii_case(&row.sha256) {
+    return Ok(Some(format!(
+      "sha256 mismatch: expected {}, found {}",
+      row.sha256, sha256
+    )));
+  }
+  // The database stores the crc32 in a signed column.
+  let crc32 = crc32.finalize() as i32;
+  if crc32 != row.crc32 {
+    return Ok(Some(format!(
+      "crc32 mismatch: expected {}, found {}",
+      row.crc32, crc32
+    )));
+  }
+  Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::NaiveDateTime;
+
+  fn row(id: i32, game_id: &str, path: Option<&str>, contents: &[u8]) -> GameData {
+    GameData {
+      id,
+      game_id: Some(game_id.to_string()),
+      title: "Data".to_string(),
+      date_added: NaiveDateTime::from_timestamp_opt(1_600_000_000, 0).unwrap(),
+      sha256: format!("{:x}", Sha256::digest(contents)),
+      crc32: crc32fast::hash(contents) as i32,
+      present_on_disk: false,
+      path: path.map(str::to_string),
+      size: contents.len() as i32,
+      parameters: None,
+    }
+  }
+
+  #[test]
+  fn scan_reports_missing_corrupt_and_orphaned() {
+    let folder = std::env::temp_dir().join(format!("fp-data-packs-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(folder.join("sub")).unwrap();
+    fs::write(folder.join("a-1600000000000.zip"), b"pack a").unwrap();
+    fs::write(folder.join("sub/b.zip"), b"pack b").unwrap();
+    fs::write(folder.join("c-1600000000000.zip"), b"tampered").unwrap();
+    fs::write(folder.join("stray.zip"), b"?").unwrap();
+
+    let rows = vec![
+      row(1, "a", None, b"pack a"),
+      row(2, "b", Some("sub/b.zip"), b"pack b"),
+      row(3, "c", None, b"pack c"),
+      row(4, "d", Some("d.zip"), b"pack d"),
+    ];
+    let mut events = vec![];
+    let (presence, report) = scan_data_packs(&folder, &rows, true, |p| events.push(p)).unwrap();
+    fs::remove_dir_all(&folder).unwrap();
+
+    assert_eq!(report.present, 2);
+    assert_eq!(report.orphaned, vec!["stray.zip"]);
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].game_data_id, 3);
+    assert_eq!(
+      report.missing,
+      vec![MissingDataPack {
+        game_data_id: 4,
+        game_id: Some("d".to_string()),
+        path: Some("d.zip".to_string()),
+      }]
+    );
+    // Pack 4 was already absent, so only packs with a changed status are written.
+    let changed: Vec<_> = presence
+      .iter()
+      .map(|p| (p.game_data_id, p.present_on_disk, p.path.clone()))
+      .collect();
+    assert_eq!(
+      changed,
+      vec![
+        (1, true, Some("a-1600000000000.zip".to_string())),
+        (2, true, Some("sub/b.zip".to_string())),
+        (3, false, Some("c-1600000000000.zip".to_string())),
+      ]
+    );
+    assert_eq!(events.last().unwrap().scanned, 4);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn unreadable_files_are_corrupt() {
+    let folder = std::env::temp_dir().join(format!("fp-data-packs-io-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(&folder).unwrap();
+    fs::write(folder.join("b.zip"), b"pack b").unwrap();
+    // A dangling link is listed like any other file, but opening it fails.
+    std::os::unix::fs::symlink(folder.join("gone"), folder.join("a.zip")).unwrap();
+
+    let rows = vec![
+      row(1, "a", Some("a.zip"), b"pack a"),
+      row(2, "b", Some("b.zip"), b"pack b"),
+    ];
+    let (_, report) = scan_data_packs(&folder, &rows, true, |_| {}).unwrap();
+    fs::remove_dir_all(&folder).unwrap();
+
+    assert_eq!(report.present, 1);
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].game_data_id, 1);
+    assert_eq!(report.corrupt[0].path, "a.zip");
+  }
+}
@@ -0,0 +1,152 @@
+//! Runs database work on tokio's blocking thread pool, so async handlers can await queries
+//! without stalling the runtime's worker threads.
+
+use flashpoint_database::types::DbState;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+#[derive(Debug, PartialEq)]
+pub enum DbJobError {
+  /// The job was cancelled, e.g. because the client that asked for it disconnected.
+  Cancelled,
+  /// The job panicked.
+  Failed(String),
+}
+
+impl std::fmt::Display for DbJobError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DbJobError::Cancelled => write!(f, "database job cancelled"),
+      DbJobError::Failed(e) => write!(f, "database job failed: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for DbJobError {}
+
+/// Cancels every job started with it. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+  inner: Arc<CancelInner>,
+}
+
+#[derive(Default)]
+struct CancelInner {
+  cancelled: AtomicBool,
+  notify: Notify,
+}
+
+impl CancelToken {
+  pub fn cancel(&self) {
+    self.inner.cancelled.store(true, Ordering::SeqCst);
+    self.inner.notify.notify_waiters();
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.inner.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Resolves once `cancel` has been called.
+  pub async fn cancelled(&self) {
+    loop {
+      // Registered before checking the flag, so a cancel in between isn't missed.
+      let notified = self.inner.notify.notified();
+      if self.is_cancelled() {
+        return;
+      }
+      notified.await;
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct AsyncDb {
+  state: Arc<DbState>,
+}
+
+impl AsyncDb {
+  pub fn new(state: Arc<DbState>) -> Self {
+    Self { state }
+  }
+
+  /// Runs `job` on the blocking thread pool. Once `cancel` fires, this returns straight away: a
+  /// job that hasn't started yet is skipped, and one that's already running finishes in the
+  /// background with its result thrown away.
+  pub async fn run<T, F>(&self, cancel: &CancelToken, job: F) -> Result<T, DbJobError>
+  where
+    F: FnOnce(&DbState) -> T + Send + 'static,
+    T: Send + 'static,
+  {
+    if cancel.is_cancelled() {
+      return Err(DbJobError::Cancelled);
+    }
+    let state = self.state.clone();
+    let job_cancel = cancel.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+      if job_cancel.is_cancelled() {
+        return None;
+      }
+      Some(job(&state))
+    });
+    tokio::select! {
+      res = handle => match res {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err(DbJobError::Cancelled),
+        Err(e) => Err(DbJobError::Failed(e.to_string())),
+      },
+      _ = cancel.cancelled() => Err(DbJobError::Cancelled),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flashpoint_database::types::DbOptions;
+  use std::sync::mpsc;
+
+  fn async_db(name: &str) -> AsyncDb {
+    let path = format!("file:{}?mode=memory&cache=shared", name);
+    AsyncDb::new(Arc::new(
+      flashpoint_database::initialize(&path, &DbOptions::default()).unwrap(),
+    ))
+  }
+
+  #[tokio::test]
+  async fn jobs_run_and_can_be_cancelled() {
+    let db = async_db("async_db_jobs");
+    let cancel = CancelToken::default();
+    let games = db
+      .run(&cancel, flashpoint_database::game::find_all_games)
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(games.is_empty());
+
+    // A running job is given up on as soon as the token fires.
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let running = {
+      let db = db.clone();
+      let cancel = cancel.clone();
+      tokio::spawn(async move {
+        db.run(&cancel, move |_| {
+          started_tx.send(()).unwrap();
+          let _ = release_rx.recv();
+        })
+        .await
+      })
+    };
+    tokio::task::spawn_blocking(move || started_rx.recv().unwrap())
+      .await
+      .unwrap();
+    cancel.cancel();
+    assert_eq!(running.await.unwrap(), Err(DbJobError::Cancelled));
+    release_tx.send(()).unwrap();
+
+    // Jobs started afterwards never run.
+    let res = db.run(&cancel, |_| panic!("should not run")).await;
+    assert_eq!(res, Err(DbJobError::Cancelled));
+  }
+}
@@ -0,0 +1,35 @@
+//! Events pushed to every connected websocket client, outside of any request/response.
+
+use futures_channel::mpsc::UnboundedSender;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct Event<'a, T> {
+  pub event: &'a str,
+  pub data: T,
+}
+
+/// Queues events for the websocket server to broadcast. Events are dropped until the server is
+/// listening.
+#[derive(Clone, Default)]
+pub struct EventSender {
+  tx: Option<UnboundedSender<String>>,
+}
+
+impl EventSender {
+  pub fn new(tx: UnboundedSender<String>) -> Self {
+    Self { tx: Some(tx) }
+  }
+
+  pub fn emit<T: Serialize>(&self, event: &str, data: T) {
+    if let Some(tx) = &self.tx {
+      match serde_json::to_string(&Event { event, data }) {
+        Ok(msg) => {
+          // Only fails once the server has shut down, when nobody is listening anyway.
+          let _ = tx.unbounded_send(msg);
+        }
+        Err(e) => println!("Failed to serialize event {}: {}", event, e),
+      }
+    }
+  }
+}
@@ -0,0 +1,111 @@
+//! Extensions in the extensions folder. An extension is a folder holding a `package.json`
+//! manifest. Running extensions is up to clients; the backend reads their manifests so clients
+//! know what's installed.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const MANIFEST: &str = "package.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionManifest {
+  pub name: String,
+  pub display_name: Option<String>,
+  pub author: Option<String>,
+  pub version: Option<String>,
+  pub description: Option<String>,
+  /// The script to run, relative to the extension's folder.
+  pub main: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Extension {
+  /// The extension's folder name.
+  pub id: String,
+  pub path: String,
+  pub manifest: ExtensionManifest,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExtensionError {
+  pub path: String,
+  pub error: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ExtensionScan {
+  /// Sorted by ID.
+  pub extensions: Vec<Extension>,
+  /// Manifests that could not be read or parsed.
+  pub errors: Vec<ExtensionError>,
+}
+
+/// Reads the manifest of every extension in `folder`. Folders without a manifest are skipped.
+pub fn load_extensions(folder: &Path) -> ExtensionScan {
+  let mut scan = ExtensionScan::default();
+  let entries = match fs::read_dir(folder) {
+    Ok(entries) => entries,
+    // No extensions folder means no extensions.
+    Err(_) => return scan,
+  };
+  for path in entries.flatten().map(|e| e.path()) {
+    let manifest_path = path.join(MANIFEST);
+    if !manifest_path.is_file() {
+      continue;
+    }
+    match load_manifest(&manifest_path) {
+      Ok(manifest) => scan.extensions.push(Extension {
+        id: path
+          .file_name()
+          .map(|n| n.to_string_lossy().to_string())
+          .unwrap_or_default(),
+        path: path.to_string_lossy().to_string(),
+        manifest,
+      }),
+      Err(e) => scan.errors.push(ExtensionError {
+        path: manifest_path.to_string_lossy().to_string(),
+        error: e.to_string(),
+      }),
+    }
+  }
+  scan.extensions.sort_by(|a, b| a.id.cmp(&b.id));
+  scan
+}
+
+fn load_manifest(path: &Path) -> Result<ExtensionManifest, Box<dyn std::error::Error>> {
+  Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_manifests_and_reports_broken_ones() {
+    let dir = std::env::temp_dir().join(format!("fp_extensions_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for name in ["good", "broken", "no-manifest"] {
+      fs::create_dir_all(dir.join(name)).unwrap();
+    }
+    fs::write(
+      dir.join("good").join(MANIFEST),
+      r#"{ "name": "good", "displayName": "Good", "main": "./index.js", "scripts": {} }"#,
+    )
+    .unwrap();
+    fs::write(dir.join("broken").join(MANIFEST), "{").unwrap();
+
+    let scan = load_extensions(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(scan.extensions.len(), 1);
+    assert_eq!(scan.extensions[0].id, "good");
+    assert_eq!(
+      scan.extensions[0].manifest.display_name.as_deref(),
+      Some("Good")
+    );
+    assert_eq!(scan.errors.len(), 1);
+    assert!(scan.errors[0].path.contains("broken"));
+  }
+}
@@ -0,0 +1,402 @@
+//! Launches games. A launch is planned first, resolving every application path and launch command
+//! so that mistakes are reported straight away, then run in the background: the additional apps
+//! marked `auto_run_before` go first, then the game itself. Every process is tracked until it
+//! exits, and clients are told about it with `app_started`, `app_exited` and `app_failed` events.
+
+use crate::events::EventSender;
+use crate::logs::Logger;
+use flashpoint_config::template::{TemplateError, TemplateVars};
+use flashpoint_config::types::AppPathOverride;
+use flashpoint_database::models::{AdditionalApp, Game};
+use flashpoint_database::types::AddAppKind;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+/// A single process to run, with its path and arguments resolved.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LaunchApp {
+  /// The game's ID for the game itself, otherwise the additional app's.
+  pub id: String,
+  pub name: String,
+  pub program: String,
+  pub args: Vec<String>,
+  /// Whether the next process waits for this one to exit.
+  pub wait_for_exit: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LaunchPlan {
+  pub game_id: String,
+  /// Additional apps that run before the game, in order.
+  pub before: Vec<LaunchApp>,
+  pub game: LaunchApp,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppEvent {
+  pub game_id: String,
+  pub app_id: String,
+  pub name: String,
+  pub pid: Option<u32>,
+  pub exit_code: Option<i32>,
+  pub error: Option<String>,
+}
+
+/// Resolves everything needed to launch `game`. Application paths go through the enabled
+/// `overrides`, and are relative to the Flashpoint folder when they point at a file in it.
+pub fn plan_launch(
+  vars: &TemplateVars,
+  game: &Game,
+  add_apps: &[AdditionalApp],
+  overrides: &[AppPathOverride],
+) -> Result<LaunchPlan, TemplateError> {
+  let app = |id: &str, name: &str, app_path: &str, launch_command: &str, wait_for_exit: bool| {
+    Ok::<_, TemplateError>(LaunchApp {
+      id: id.to_string(),
+      name: name.to_string(),
+      program: resolve_program(vars, &vars.resolve_app_path(app_path, overrides)?),
+      args: split_args(&vars.expand(launch_command)?),
+      wait_for_exit,
+    })
+  };
+  let before = add_apps
+    .iter()
+    .filter(|a| a.auto_run_before)
+    .filter_map(|a| match a.kind() {
+      AddAppKind::Executable { path, args } => {
+        Some(app(&a.id, &a.name, &path, &args, a.wait_for_exit))
+      }
+      // Messages and extras are shown by the client, there's nothing to run.
+      AddAppKind::Message { .. } | AddAppKind::Extras { .. } => None,
+    })
+    .collect::<Result<_, _>>()?;
+  Ok(LaunchPlan {
+    game_id: game.id.clone(),
+    before,
+    game: app(
+      &game.id,
+      &game.title,
+      &game.application_path,
+      &game.launch_command,
+      false,
+    )?,
+  })
+}
+
+fn resolve_program(vars: &TemplateVars, app_path: &str) -> String {
+  // Application paths from the Windows days use backslashes.
+  let local = PathBuf::from(&vars.fp_path).join(app_path.replace('\\', "/"));
+  if local.is_file() {
+    local.to_string_lossy().to_string()
+  } else {
+    app_path.to_string()
+  }
+}
+
+/// Splits a launch command into arguments on whitespace. Double quotes group an argument that
+/// contains spaces.
+pub fn split_args(command: &str) -> Vec<String> {
+  let mut args = vec![];
+  let mut current = String::new();
+  let mut in_arg = false;
+  let mut quoted = false;
+  for c in command.chars() {
+    match c {
+      '"' => {
+        quoted = !quoted;
+        in_arg = true;
+      }
+      c if c.is_whitespace() && !quoted => {
+        if in_arg {
+          args.push(std::mem::take(&mut current));
+          in_arg = false;
+        }
+      }
+      c => {
+        current.push(c);
+        in_arg = true;
+      }
+    }
+  }
+  if in_arg {
+    args.push(current);
+  }
+  args
+}
+
+/// Runs launch plans and keeps track of the processes that are still running.
+#[derive(Clone, Default)]
+pub struct Launcher {
+  fp_path: PathBuf,
+  events: EventSender,
+  logger: Logger,
+  running: Arc<Mutex<Vec<AppEvent>>>,
+}
+
+impl Launcher {
+  /// Processes run in `fp_path`.
+  pub fn new(fp_path: PathBuf, events: EventSender, logger: Logger) -> Self {
+    Self {
+      fp_path,
+      events,
+      logger,
+      running: Arc::default(),
+    }
+  }
+
+  /// Runs the plan in the background. The returned task finishes once the game exits. Must be
+  /// called from within a tokio runtime.
+  pub fn launch(&self, plan: LaunchPlan) -> JoinHandle<()> {
+    let launcher = self.clone();
+    tokio::spawn(async move {
+      for app in &plan.before {
+        if let Some(child) = launcher.spawn(&plan.game_id, app) {
+          let track = launcher
+            .clone()
+            .track(plan.game_id.clone(), app.clone(), child);
+          if app.wait_for_exit {
+            track.await;
+          } else {
+            tokio::spawn(track);
+          }
+        }
+      }
+      if let Some(child) = launcher.spawn(&plan.game_id, &plan.game) {
+        launcher.track(plan.game_id.clone(), plan.game, child).await;
+      }
+    })
+  }
+
+  /// The processes started by launches that haven't exited yet.
+  pub fn running(&self) -> Vec<AppEvent> {
+    self.running.lock().unwrap().clone()
+  }
+
+  fn spawn(&self, game_id: &str, app: &LaunchApp) -> Option<Child> {
+    let mut event = AppEvent {
+      game_id: game_id.to_string(),
+      app_id: app.id.clone(),
+      name: app.name.clone(),
+      pid: None,
+      exit_code: None,
+      error: None,
+    };
+    let res = Command::new(&app.program)
+      .args(&app.args)
+      .current_dir(&self.fp_path)
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn();
+    match res {
+      Ok(mut child) => {
+        self.logger.capture_output(&mut child, &app.name);
+        event.pid = child.id();
+        self.running.lock().unwrap().push(event.clone());
+        self.events.emit("app_started", &event);
+        Some(child)
+      }
+      Err(e) => {
+        let msg = format!("Failed to launch {}: {}", app.program, e);
+        self.logger.error(&app.name, &msg);
+        event.error = Some(e.to_string());
+        self.events.emit("app_failed", &event);
+        None
+      }
+    }
+  }
+
+  async fn track(self, game_id: String, app: LaunchApp, mut child: Child) {
+    let pid = child.id();
+    let mut event = AppEvent {
+      game_id,
+      app_id: app.id,
+      name: app.name,
+      pid,
+      exit_code: None,
+      error: None,
+    };
+    match child.wait().await {
+      Ok(status) => event.exit_code = status.code(),
+      Err(e) => event.error = Some(e.to_string()),
+    }
+    self
+      .running
+      .lock()
+      .unwrap()
+      .retain(|r| !(r.pid == pid && r.app_id == event.app_id));
+    self.events.emit("app_exited", &event);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flashpoint_database::test_game;
+
+  fn vars(fp_path: &str) -> TemplateVars {
+    TemplateVars {
+      fp_path: fp_path.to_string(),
+      os: "linux".to_string(),
+      cwd: "/".to_string(),
+    }
+  }
+
+  fn add_app(id: &str, app_path: &str, launch_command: &str, wait_for_exit: bool) -> AdditionalApp {
+    AdditionalApp {
+      id: id.to_string(),
+      application_path: app_path.to_string(),
+      auto_run_before: true,
+      launch_command: launch_command.to_string(),
+      name: id.to_string(),
+      wait_for_exit,
+      parent_game_id: Some("game".to_string()),
+    }
+  }
+
+  #[test]
+  fn splits_launch_commands() {
+    assert_eq!(
+      split_args(r#"  http://a.com/b.swf "C:\My Games\c d.exe" -x"y z"  "" "#),
+      vec!["http://a.com/b.swf", r"C:\My Games\c d.exe", "-xy z", ""]
+    );
+    assert!(split_args("   ").is_empty());
+  }
+
+  #[test]
+  fn plans_use_enabled_overrides() {
+    let mut game = test_game("game", "Game");
+    game.application_path = "FPSoftware/Flash.exe".to_string();
+    game.launch_command = "<fpPath>/game.swf --fast".to_string();
+    let mut readme = add_app("readme", ":message:", "Hi", false);
+    readme.auto_run_before = true;
+    let mut manual = add_app("manual", "manual", "", false);
+    manual.auto_run_before = false;
+    let overrides = vec![
+      AppPathOverride {
+        path: "FPSoftware/Flash.exe".to_string(),
+        r#override: "ignored".to_string(),
+        enabled: false,
+      },
+      AppPathOverride {
+        path: "FPSoftware/Flash.exe".to_string(),
+        r#override: "flashplayer".to_string(),
+        enabled: true,
+      },
+    ];
+    let plan = plan_launch(
+      &vars("/nowhere"),
+      &game,
+      &[readme, manual, add_app("server", "php", "-S", true)],
+      &overrides,
+    )
+    .unwrap();
+    assert_eq!(plan.game.program, "flashplayer");
+    assert_eq!(plan.game.args, vec!["/nowhere/game.swf", "--fast"]);
+    assert_eq!(plan.before.len(), 1);
+    assert_eq!(plan.before[0].id, "server");
+    assert!(plan.before[0].wait_for_exit);
+
+    game.launch_command = "<nope>".to_string();
+    assert!(plan_launch(&vars("/nowhere"), &game, &[], &overrides).is_err());
+  }
+
+  #[test]
+  fn resolves_programs_with_backslashes() {
+    let dir = std::env::temp_dir().join(format!("fp_launcher_paths_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("FPSoftware").join("Flash")).unwrap();
+    let player = dir.join("FPSoftware/Flash/flashplayer.exe");
+    std::fs::write(&player, "").unwrap();
+
+    let vars = vars(dir.to_str().unwrap());
+    let resolved = resolve_program(&vars, r"FPSoftware\Flash\flashplayer.exe");
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(resolved, player.to_string_lossy());
+    // Programs outside the Flashpoint folder are left for the OS to find.
+    assert_eq!(
+      resolve_program(&vars, r"Missing\app.exe"),
+      r"Missing\app.exe"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test(flavor = "multi_thread")]
+  async fn runs_apps_before_the_game() {
+    use futures_channel::mpsc::unbounded;
+    use futures_util::StreamExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("fp_launcher_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let stub = |name: &str, body: &str| {
+      let path = dir.join(name);
+      std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+      std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    stub("before.sh", "sleep 0.2\necho \"$1\" > before.txt");
+    // Exits with 1 if the app it should have waited for hasn't finished, and with 7 otherwise.
+    stub(
+      "game.sh",
+      "test -f before.txt || exit 1\necho playing\nexit 7",
+    );
+
+    let mut game = test_game("game", "Game");
+    game.application_path = "game.sh".to_string();
+    let add_apps = [
+      add_app("before", "before.sh", "\"first arg\"", true),
+      add_app("missing", "does-not-exist", "", false),
+    ];
+    let plan = plan_launch(&vars(dir.to_str().unwrap()), &game, &add_apps, &[]).unwrap();
+
+    let (tx, rx) = unbounded();
+    let logger = Logger::default();
+    let launcher = Launcher::new(dir.clone(), EventSender::new(tx), logger.clone());
+    launcher.launch(plan).await.unwrap();
+    assert!(launcher.running().is_empty());
+    assert_eq!(
+      std::fs::read_to_string(dir.join("before.txt")).unwrap(),
+      "first arg\n"
+    );
+
+    drop(launcher);
+    let events: Vec<serde_json::Value> = rx
+      .map(|e| serde_json::from_str(&e).unwrap())
+      .collect()
+      .await;
+    let find = |event: &str, app_id: &str| {
+      events
+        .iter()
+        .find(|e| e["event"] == event && e["data"]["appId"] == app_id)
+        .map(|e| e["data"].clone())
+    };
+    assert!(find("app_started", "game").unwrap()["pid"].is_number());
+    assert_eq!(find("app_exited", "game").unwrap()["exitCode"], 7);
+    assert_eq!(find("app_exited", "before").unwrap()["exitCode"], 0);
+    assert!(find("app_failed", "missing").is_some());
+
+    // Output goes to the log, under the app's name, once the pipe has been read.
+    let logged = || {
+      logger
+        .entries()
+        .iter()
+        .any(|e| e.source == game.title && e.content == "playing")
+    };
+    for _ in 0..100 {
+      if logged() {
+        break;
+      }
+      tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(logged());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -1,34 +1,64 @@
 use cfg_if::cfg_if;
+#[cfg(any(feature = "services", feature = "websocket"))]
+use flashpoint_config::template::TemplateVars;
 use flashpoint_config::types::{Config, Preferences};
-use flashpoint_database::types::DbState;
-use std::path::Path;
+use flashpoint_database::types::{DbOptions, DbState};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::fs::File;
+use tokio::io::AsyncReadExt;
 
 cfg_if!(
   if #[cfg(feature = "services")] {
+    pub mod supervisor;
     use flashpoint_config::types::Services;
+    use supervisor::ServiceSupervisor;
   }
 );
 cfg_if!(
   if #[cfg(feature = "websocket")] {
     mod ws;
     use ws::*;
+    use db::{AsyncDb, CancelToken};
+    use flashpoint_database::models::GameRelation;
+    use flashpoint_database::types::RandomGameOpts;
     use std::collections::HashMap;
-    use std::sync::Arc;
-    use std::sync::Mutex;
-    use tokio::io::AsyncReadExt;
     use tokio::net::{TcpListener, TcpStream};
     use tokio_tungstenite::tungstenite::Message;
     use futures_channel::mpsc::{unbounded, UnboundedSender};
     use std::net::SocketAddr;
     use futures_util::StreamExt;
+    use watcher::{FolderChange, FolderWatcher, WatchOptions};
     type Tx = UnboundedSender<Message>;
     type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
   }
 );
 
+pub mod data_packs;
+pub mod db;
+pub mod events;
+pub mod extensions;
+pub mod launcher;
+pub mod logs;
+pub mod playlists;
 pub mod signals;
+pub mod sources;
+pub mod themes;
+pub mod watcher;
+use events::EventSender;
+use extensions::ExtensionScan;
+use launcher::Launcher;
+use logs::Logger;
+use playlists::PlaylistSync;
 use signals::*;
+use themes::Theme;
+use watcher::WatchedFolder;
+
+/// How often the folders listed in services.json are rescanned. They can be large, and nothing
+/// reloads when they change, so they're only checked now and then.
+#[cfg(feature = "services")]
+const SERVICES_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Copy, Debug)]
 pub enum InitLoad {
@@ -49,14 +79,25 @@ pub struct FlashpointSignals {
 }
 
 pub struct FlashpointService {
-  pub db: DbState,
+  pub db: Arc<DbState>,
   pub initialized: bool,
   pub base_path: String,
   pub config: Config,
   pub prefs: Preferences,
   #[cfg(feature = "services")]
   pub services_info: Services,
+  /// Runs the background services, once the websocket server is listening.
+  #[cfg(feature = "services")]
+  pub supervisor: Option<Arc<ServiceSupervisor>>,
   pub signals: FlashpointSignals,
+  pub playlist_sync: Arc<Mutex<PlaylistSync>>,
+  /// Held while preferences are saved, so concurrent updates don't overwrite each other.
+  pub prefs_lock: Arc<Mutex<()>>,
+  pub themes: Vec<Theme>,
+  pub extensions: ExtensionScan,
+  pub events: EventSender,
+  pub logger: Logger,
+  pub launcher: Launcher,
 }
 
 impl FlashpointService {
@@ -79,26 +120,52 @@ impl FlashpointService {
       .unwrap()
       .to_string();
 
+    let playlists_path = base_path
+      .join(config.flashpoint_path.clone())
+      .join(prefs.playlist_folder_path.clone());
+
     #[cfg(feature = "services")]
     let services_info = load_services(
       &base_path
         .join(config.flashpoint_path.clone())
         .join(prefs.json_folder_path.clone())
         .join("services.json"),
+      &TemplateVars::new(&base_path.join(&config.flashpoint_path)),
     )
     .await?;
+    // Fail early rather than quietly running without a server.
+    #[cfg(feature = "services")]
+    if config.start_server {
+      supervisor::find_server(&services_info, &config.server)?;
+    }
+
+    let db_opts = DbOptions {
+      pool_size: config.database_pool_size,
+      busy_timeout: Duration::from_millis(config.database_busy_timeout),
+    };
+    let db = flashpoint_database::initialize(&db_path, &db_opts)?;
+    flashpoint_database::filter::set_game_filters(&db, &prefs);
     Ok(Self {
-      db: flashpoint_database::initialize(&db_path)?,
+      db: Arc::new(db),
       initialized: false,
       base_path: base_path.canonicalize()?.to_str().unwrap().to_string(),
       config,
       prefs: prefs.clone(),
       #[cfg(feature = "services")]
       services_info,
+      #[cfg(feature = "services")]
+      supervisor: None,
       signals: FlashpointSignals {
         exit_code: ExitSignal::new(),
         init_load: InitLoadSignal::new(),
       },
+      playlist_sync: Arc::new(Mutex::new(PlaylistSync::new(playlists_path))),
+      prefs_lock: Arc::new(Mutex::new(())),
+      events: EventSender::default(),
+      themes: vec![],
+      extensions: ExtensionScan::default(),
+      logger: Logger::default(),
+      launcher: Launcher::default(),
     })
   }
 
@@ -110,9 +177,22 @@ impl FlashpointService {
     self.signals.init_load.emit(InitLoad::Services);
     // TODO
     self.signals.init_load.emit(InitLoad::Database);
-    // TODO
+    let report = self
+      .playlist_sync
+      .lock()
+      .unwrap()
+      .import_all(&self.db)
+      .clone();
+    for err in &report.errors {
+      println!("Failed to load playlist {}: {}", err.path, err.error);
+    }
     self.signals.init_load.emit(InitLoad::Playlists);
-    // TODO
+    self.themes = themes::load_themes(&self.fp_path().join(&self.prefs.theme_folder_path));
+    self.extensions =
+      extensions::load_extensions(&self.fp_path().join(&self.prefs.extensions_path));
+    for err in &self.extensions.errors {
+      println!("Failed to load extension {}: {}", err.path, err.error);
+    }
     self.signals.init_load.emit(InitLoad::Extensions);
     // TODO
     self.signals.init_load.emit(InitLoad::ExecMappings);
@@ -127,10 +207,10 @@ impl FlashpointService {
   }
 
   #[cfg(feature = "websocket")]
-  pub async fn listen(self) {
+  pub async fn listen(mut self) {
     use std::process::exit;
 
-    let registers = Arc::new(Mutex::new(WebsocketRegisters {
+    let registers = Arc::new(WebsocketRegisters {
       init_data: Box::new(|fp_service, _: ()| {
         Ok(WebsocketRes {
           data: InitDataRes {
@@ -138,61 +218,418 @@ impl FlashpointService {
             prefs: fp_service.prefs.clone(),
             #[cfg(feature = "services")]
             services_info: fp_service.services_info.clone(),
+            themes: fp_service.themes.clone(),
+            extensions: fp_service.extensions.clone(),
           },
         })
       }),
-      view_all_games: Box::new(|mut fp_service, _| {
+      update_prefs: Box::new(|handle, patch| {
+        Ok(WebsocketRes {
+          data: handle.update_prefs(patch)?,
+        })
+      }),
+      view_all_games: Box::new(|db, _| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::view_all_games(db)?,
+        })
+      }),
+      all_games: Box::new(|db, _| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::find_all_games(db)?,
+        })
+      }),
+      all_tag_categories: Box::new(|db, _| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::find_tag_categories(db)?,
+        })
+      }),
+      create_tag_category: Box::new(|db, data| {
         Ok(WebsocketRes {
-          data: flashpoint_database::game::view_all_games(&mut fp_service.db),
+          data: flashpoint_database::tag::create_tag_category(db, data)?,
         })
       }),
-      all_games: Box::new(|mut fp_service, _| {
+      find_tag_by_name: Box::new(|db, data| {
+        let (tag, aliases) = flashpoint_database::tag::find_tag_by_name(db, data)?;
         Ok(WebsocketRes {
-          data: flashpoint_database::game::find_all_games(&mut fp_service.db),
+          data: TagRes::new(tag, aliases)?,
         })
       }),
-      all_tag_categories: Box::new(|mut fp_service, _| {
+      find_tags: Box::new(|db, _| {
+        let tags = flashpoint_database::tag::find_tags(db)?;
         Ok(WebsocketRes {
-          data: flashpoint_database::tag::find_tag_categories(&mut fp_service.db),
+          data: tags
+            .into_iter()
+            .map(|(tag, aliases)| TagRes::new(tag, aliases))
+            .collect::<Result<Vec<TagRes>, _>>()?,
         })
       }),
-      create_tag_category: Box::new(|mut fp_service, data| {
+      get_tag: Box::new(|db, data| {
+        let (tag, aliases) = flashpoint_database::tag::get_tag_by_id(db, data)?;
         Ok(WebsocketRes {
-          data: flashpoint_database::tag::create_tag_category(&mut fp_service.db, data)?,
+          data: TagRes::new(tag, aliases)?,
         })
       }),
-      find_tag_by_name: Box::new(|mut fp_service, data| {
-        let (tag, aliases) = flashpoint_database::tag::find_tag_by_name(&mut fp_service.db, data)?;
-        let primary = aliases
-          .iter()
-          .find(|a| a.id == tag.primary_alias_id.unwrap())
-          .unwrap();
+      create_tag: Box::new(|db, data| {
+        let (tag, aliases) = flashpoint_database::tag::create_tag(db, data)?;
         Ok(WebsocketRes {
-          data: TagRes {
-            id: tag.id,
-            date_modified: tag.date_modified,
-            category_id: tag.category_id,
-            description: tag.description,
-            primary_alias: primary.clone(),
-            aliases,
+          data: TagRes::new(tag, aliases)?,
+        })
+      }),
+      delete_tag: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::delete_tag(db, data)?,
+        })
+      }),
+      save_tag: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::save_tag(db, data.id, data.changes)?,
+        })
+      }),
+      add_alias_to_tag: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::add_alias_to_tag(db, data.tag_id, data.name)?,
+        })
+      }),
+      save_tag_alias: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::save_tag_alias(db, data.id, data.name)?,
+        })
+      }),
+      remove_tag_alias: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::remove_tag_alias(db, data)?,
+        })
+      }),
+      set_primary_alias: Box::new(|db, data| {
+        let (tag, aliases) =
+          flashpoint_database::tag::set_primary_alias(db, data.tag_id, data.alias_id)?;
+        Ok(WebsocketRes {
+          data: TagRes::new(tag, aliases)?,
+        })
+      }),
+      merge_tags: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::merge_tags(
+            db,
+            data.source_id,
+            data.target_id,
+            data.move_aliases,
+          )?,
+        })
+      }),
+      find_tag_suggestions: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::tag::find_tag_suggestions(db, data.partial, data.limit)?,
+        })
+      }),
+      find_playlists: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::playlist::find_playlists(db, data)?,
+        })
+      }),
+      find_playlist: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::playlist::find_playlist(db, data)?,
+        })
+      }),
+      create_playlist: Box::new(|handle, data| {
+        let p = flashpoint_database::playlist::create_playlist(&handle.db, data)?;
+        handle.sync_playlist(&p.id);
+        Ok(WebsocketRes { data: p })
+      }),
+      update_playlist: Box::new(|handle, data| {
+        let p = flashpoint_database::playlist::update_playlist(&handle.db, data)?;
+        handle.sync_playlist(&p.id);
+        Ok(WebsocketRes { data: p })
+      }),
+      remove_playlist: Box::new(|handle, data| {
+        let removed = flashpoint_database::playlist::remove_playlist(&handle.db, data.clone())?;
+        if let Err(e) = handle.playlist_sync.lock().unwrap().remove(&data) {
+          println!("Failed to remove playlist file {}: {}", data, e);
+        }
+        Ok(WebsocketRes { data: removed })
+      }),
+      find_playlist_games: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::playlist::find_playlist_games(db, data)?,
+        })
+      }),
+      add_playlist_game: Box::new(|handle, data| {
+        let entry = flashpoint_database::playlist::add_playlist_game(
+          &handle.db,
+          data.playlist_id,
+          data.game_id,
+          data.notes,
+        )?;
+        handle.sync_playlist(&entry.playlist_id);
+        Ok(WebsocketRes { data: entry })
+      }),
+      remove_playlist_game: Box::new(|handle, data| {
+        let removed = flashpoint_database::playlist::remove_playlist_game(
+          &handle.db,
+          data.playlist_id.clone(),
+          data.game_id,
+        )?;
+        handle.sync_playlist(&data.playlist_id);
+        Ok(WebsocketRes { data: removed })
+      }),
+      update_playlist_game: Box::new(|handle, data| {
+        let entry =
+          flashpoint_database::playlist::update_playlist_game(&handle.db, data.id, data.notes)?;
+        handle.sync_playlist(&entry.playlist_id);
+        Ok(WebsocketRes { data: entry })
+      }),
+      move_playlist_game: Box::new(|handle, data| {
+        let entries = flashpoint_database::playlist::move_playlist_game(
+          &handle.db,
+          data.playlist_id.clone(),
+          data.game_id,
+          data.index,
+        )?;
+        handle.sync_playlist(&data.playlist_id);
+        Ok(WebsocketRes { data: entries })
+      }),
+      update_playlist_games: Box::new(|handle, data| {
+        let entries = flashpoint_database::playlist::update_playlist_games(
+          &handle.db,
+          data.playlist_id.clone(),
+          data.game_ids,
+        )?;
+        handle.sync_playlist(&data.playlist_id);
+        Ok(WebsocketRes { data: entries })
+      }),
+      playlist_sync_report: Box::new(|fp_service, _| {
+        Ok(WebsocketRes {
+          data: fp_service.playlist_sync.lock().unwrap().report.clone(),
+        })
+      }),
+      find_games_with_tag: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::find_games_with_tag(db, data)?,
+        })
+      }),
+      find_add_app: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::find_add_app(db, data)?,
+        })
+      }),
+      find_add_apps: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::find_add_apps(db, data)?,
+        })
+      }),
+      create_add_app: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::create_add_app(db, data)?,
+        })
+      }),
+      save_add_app: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::save_add_app(db, data)?,
+        })
+      }),
+      remove_add_app: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game::remove_add_app(db, data)?,
+        })
+      }),
+      find_game_data: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game_data::find_game_data(db, data)?,
+        })
+      }),
+      import_game_data: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game_data::import_game_data(db, data)?,
+        })
+      }),
+      set_active_game_data: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game_data::set_active_game_data(
+            db,
+            data.game_id,
+            data.game_data_id,
+          )?,
+        })
+      }),
+      set_game_data_present: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::game_data::set_game_data_present(db, data.id, data.path)?,
+        })
+      }),
+      scan_data_packs: Box::new(|handle, data| {
+        let folder = handle.fp_path.join(&handle.prefs.data_packs_folder_path);
+        let report = data_packs::scan_and_update(&handle.db, &folder, data.verify, |progress| {
+          handle.events.emit("data_pack_scan_progress", progress)
+        })?;
+        Ok(WebsocketRes { data: report })
+      }),
+      find_sources: Box::new(|db, _| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::source::find_sources(db)?,
+        })
+      }),
+      register_source: Box::new(|handle, data| {
+        // Relative index paths are relative to the Flashpoint folder.
+        let index_path = handle.fp_path.join(&data.index_path);
+        Ok(WebsocketRes {
+          data: sources::register_source_file(&handle.db, data.name, data.base_url, &index_path)?,
+        })
+      }),
+      remove_source: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::source::remove_source(db, data)?,
+        })
+      }),
+      find_source_urls: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::source::find_source_urls(db, data)?,
+        })
+      }),
+      search_games: Box::new(|db, mut data| {
+        if let Some(query) = &data.query {
+          match flashpoint_database::search::parse_search(query) {
+            Ok(search) => data.filters.search_query = Some(search),
+            Err(errors) => {
+              return Ok(WebsocketRes {
+                data: SearchGamesRes { page: None, errors },
+              })
+            }
+          }
+        }
+        let order = match &data.order_by {
+          Some(name) => Some((
+            GameRelation::from_column_name(name)
+              .ok_or_else(|| format!("unknown order column {}", name))?,
+            data.ascending,
+          )),
+          None => None,
+        };
+        let page = flashpoint_database::game::search_games(db, data.filters, order, data.cursor)?;
+        Ok(WebsocketRes {
+          data: SearchGamesRes {
+            page: Some(page),
+            errors: vec![],
           },
         })
       }),
-      find_games_with_tag: Box::new(|mut fp_service, data| {
+      find_random_games: Box::new(|db, data| {
+        let search_query = match &data.query {
+          Some(query) => match flashpoint_database::search::parse_search(query) {
+            Ok(search) => Some(search),
+            Err(errors) => {
+              return Ok(WebsocketRes {
+                data: RandomGamesRes {
+                  games: vec![],
+                  errors,
+                },
+              })
+            }
+          },
+          None => None,
+        };
+        let opts = RandomGameOpts {
+          count: data.count,
+          library: data.library,
+          search_query,
+        };
         Ok(WebsocketRes {
-          data: flashpoint_database::game::find_games_with_tag(&mut fp_service.db, data),
+          data: RandomGamesRes {
+            games: flashpoint_database::game::find_random_games(db, opts)?,
+            errors: vec![],
+          },
         })
       }),
+      find_unique_values: Box::new(|db, data| {
+        let column = GameRelation::from_column_name(&data.column)
+          .ok_or_else(|| format!("unknown column {}", data.column))?;
+        Ok(WebsocketRes {
+          data: flashpoint_database::misc::find_unique_values(db, &column, data.library)?,
+        })
+      }),
+      find_unique_values_in_order: Box::new(|db, data| {
+        let column = GameRelation::from_column_name(&data.column)
+          .ok_or_else(|| format!("unknown column {}", data.column))?;
+        Ok(WebsocketRes {
+          data: flashpoint_database::misc::find_unique_values_in_order(db, &column, data.library)?,
+        })
+      }),
+      find_platforms: Box::new(|db, data| {
+        Ok(WebsocketRes {
+          data: flashpoint_database::misc::find_platforms(db, data.library)?,
+        })
+      }),
+      #[cfg(feature = "services")]
+      switch_server: Box::new(|mut fp_service, name| {
+        let info = supervisor::find_server(&fp_service.services_info, &name)?.clone();
+        if let Some(supervisor) = &fp_service.supervisor {
+          supervisor.set_server(&info);
+        }
+        fp_service.config.server = name;
+        Ok(WebsocketRes { data: info })
+      }),
+      launch_game: Box::new(|handle, game_id| {
+        let game = flashpoint_database::game::find_game(&handle.db, game_id.clone())?;
+        let add_apps = flashpoint_database::game::find_add_apps(&handle.db, game_id)?;
+        let plan = launcher::plan_launch(
+          &TemplateVars::new(&handle.fp_path),
+          &game,
+          &add_apps,
+          &handle.prefs.app_path_overrides,
+        )?;
+        handle.launcher.launch(plan.clone());
+        Ok(WebsocketRes { data: plan })
+      }),
+      parse_search: Box::new(|_, data| {
+        let res = match flashpoint_database::search::parse_search(&data) {
+          Ok(search) => ParseSearchRes {
+            search: Some(search),
+            errors: vec![],
+          },
+          Err(errors) => ParseSearchRes {
+            search: None,
+            errors,
+          },
+        };
+        Ok(WebsocketRes { data: res })
+      }),
       add: Box::new(|_, data| {
         Ok(WebsocketRes {
           data: data.first + data.second,
         })
       }),
-    }));
+    });
 
     // Create listener state
-    let fp_state = Arc::new(Mutex::new(self));
     let state = PeerMap::new(Mutex::new(HashMap::new()));
+    let (event_tx, mut event_rx) = unbounded::<String>();
+    self.events = EventSender::new(event_tx);
+    self.logger = self.logger.with_events(self.events.clone());
+    self.launcher = Launcher::new(self.fp_path(), self.events.clone(), self.logger.clone());
+    #[cfg(feature = "services")]
+    self.start_services();
+    let folders = self.watched_folders();
+    let fp_state = Arc::new(Mutex::new(self));
+
+    // Reload what changed on disk, and let clients know about it
+    let watch_fp_state = fp_state.clone();
+    let folders_fp_state = fp_state.clone();
+    FolderWatcher::new(folders, WatchOptions::default()).spawn(
+      // The theme and extension folders move when the preferences change.
+      move || folders_fp_state.lock().unwrap().watched_folders(),
+      move |change| on_folder_change(&watch_fp_state, change),
+    );
+
+    // Broadcast events to every peer
+    let event_peers = state.clone();
+    tokio::spawn(async move {
+      while let Some(event) = event_rx.next().await {
+        let msg = Message::text(event);
+        for tx in event_peers.lock().unwrap().values() {
+          let _ = tx.unbounded_send(msg.clone());
+        }
+      }
+    });
     let addr = "127.0.0.1:9001";
     let listener = TcpListener::bind(addr)
       .await
@@ -202,8 +639,21 @@ impl FlashpointService {
 
     // Capture Ctrl+C to trigger Exit signal on FlashpointService
     let ctrlc_fp_state = fp_state.clone();
+    #[cfg(feature = "services")]
+    let (runtime, supervisor, services_info) = {
+      let fp = fp_state.lock().unwrap();
+      (
+        tokio::runtime::Handle::current(),
+        fp.supervisor.clone(),
+        fp.services_info.clone(),
+      )
+    };
     ctrlc::set_handler(move || {
       println!("Received Exit Signal, shutting down...");
+      #[cfg(feature = "services")]
+      if let Some(supervisor) = &supervisor {
+        runtime.block_on(supervisor.stop(&services_info));
+      }
       match ctrlc_fp_state.lock() {
         Ok(fp) => {
           fp.signals.exit_code.emit(0);
@@ -229,16 +679,169 @@ impl FlashpointService {
     }
   }
 
+  /// Starts the `start` entries, daemons and, if `start_server` is set, the server named in the
+  /// config. `new` already checked that the server exists.
+  #[cfg(all(feature = "services", feature = "websocket"))]
+  fn start_services(&mut self) {
+    let supervisor = ServiceSupervisor::new(
+      self.fp_path(),
+      self.events.clone(),
+      self.logger.clone(),
+      supervisor::SupervisorOptions::default(),
+    );
+    let server = if self.config.start_server {
+      supervisor::find_server(&self.services_info, &self.config.server).ok()
+    } else {
+      None
+    };
+    supervisor.start(&self.services_info, server);
+    self.supervisor = Some(Arc::new(supervisor));
+  }
+
+  /// The playlist, theme and extension folders, and the folders listed in services.json.
+  pub fn watched_folders(&self) -> Vec<WatchedFolder> {
+    let fp_path = self.fp_path();
+    let folder = |kind: &str, path: PathBuf, depth: usize| WatchedFolder {
+      kind: kind.to_string(),
+      path,
+      depth,
+      poll_interval: None,
+    };
+    #[allow(unused_mut)]
+    let mut folders = vec![
+      folder(
+        "playlists",
+        self.playlist_sync.lock().unwrap().folder().to_path_buf(),
+        1,
+      ),
+      // Each theme and extension is a folder, and only the files at its top level matter.
+      folder("themes", fp_path.join(&self.prefs.theme_folder_path), 2),
+      folder("extensions", fp_path.join(&self.prefs.extensions_path), 2),
+    ];
+    #[cfg(feature = "services")]
+    folders.extend(self.services_info.watch.iter().map(|w| WatchedFolder {
+      poll_interval: Some(SERVICES_POLL_INTERVAL),
+      ..folder("services", fp_path.join(w), 1)
+    }));
+    folders
+  }
+
+  /// The Flashpoint folder, which paths in the preferences are relative to.
+  pub fn fp_path(&self) -> PathBuf {
+    Path::new(&self.base_path).join(&self.config.flashpoint_path)
+  }
+
+  /// Copies out what a register needs to run without the service lock. `service` is the mutex
+  /// this service lives in.
+  #[cfg(feature = "websocket")]
+  fn handle(&self, service: &Arc<Mutex<FlashpointService>>) -> ServiceHandle {
+    ServiceHandle {
+      service: service.clone(),
+      prefs_lock: self.prefs_lock.clone(),
+      db: self.db.clone(),
+      fp_path: self.fp_path(),
+      prefs: self.prefs.clone(),
+      events: self.events.clone(),
+      playlist_sync: self.playlist_sync.clone(),
+      logger: self.logger.clone(),
+      launcher: self.launcher.clone(),
+    }
+  }
+
   pub fn exit(&self) {
     self.signals.exit_code.emit(0);
   }
 }
 
+/// Merges `patch` into `prefs`, then saves the result to the preferences.json in `fp_path`,
+/// keeping the fields we don't know about. The file is replaced with a rename, so a crash while
+/// saving can't leave it truncated.
+pub fn save_prefs(
+  fp_path: &Path,
+  prefs: &Preferences,
+  patch: serde_json::Value,
+) -> Result<Preferences, Box<dyn std::error::Error>> {
+  let mut merged = serde_json::to_value(prefs)?;
+  merge_json(&mut merged, patch);
+  let prefs: Preferences = serde_json::from_value(merged)?;
+
+  let path = fp_path.join("preferences.json");
+  let mut file = match std::fs::read_to_string(&path) {
+    Ok(contents) => serde_json::from_str(&contents)?,
+    Err(_) => serde_json::json!({}),
+  };
+  merge_json(&mut file, serde_json::to_value(&prefs)?);
+  let tmp_path = path.with_extension("json.tmp");
+  std::fs::write(&tmp_path, serde_json::to_string_pretty(&file)?)?;
+  std::fs::rename(&tmp_path, &path)?;
+  Ok(prefs)
+}
+
+/// Copies the top level fields of `patch` over those of `target`.
+fn merge_json(target: &mut serde_json::Value, patch: serde_json::Value) {
+  match (target, patch) {
+    (serde_json::Value::Object(target), serde_json::Value::Object(patch)) => target.extend(patch),
+    (target, patch) => *target = patch,
+  }
+}
+
+/// Reloads whatever lives in the changed folder, then lets clients know. Runs on the watcher's
+/// blocking thread, and only holds the service lock to copy handles out and store the results.
+#[cfg(feature = "websocket")]
+fn on_folder_change(fp_state: &Arc<Mutex<FlashpointService>>, change: FolderChange) {
+  let handle = match fp_state.lock() {
+    Ok(fp_service) => fp_service.handle(fp_state),
+    Err(e) => {
+      println!("Error handling folder change: {}", e);
+      return;
+    }
+  };
+  match change.kind.as_str() {
+    "playlists" => {
+      let paths: Vec<PathBuf> = change.changed.iter().map(PathBuf::from).collect();
+      let report = handle
+        .playlist_sync
+        .lock()
+        .unwrap()
+        .apply_changes(&handle.db, &paths)
+        .clone();
+      for err in &report.errors {
+        let msg = format!("Failed to load playlist {}: {}", err.path, err.error);
+        handle.logger.error("Playlists", &msg);
+      }
+      handle.events.emit("playlists_reloaded", &report);
+    }
+    "themes" => {
+      let themes = themes::load_themes(&handle.fp_path.join(&handle.prefs.theme_folder_path));
+      if let Ok(mut fp_service) = fp_state.lock() {
+        fp_service.themes = themes.clone();
+      }
+      handle.events.emit("themes_reloaded", &themes);
+    }
+    "extensions" => {
+      let scan = extensions::load_extensions(&handle.fp_path.join(&handle.prefs.extensions_path));
+      for err in &scan.errors {
+        let msg = format!("Failed to load extension {}: {}", err.path, err.error);
+        handle.logger.error("Extensions", &msg);
+      }
+      if let Ok(mut fp_service) = fp_state.lock() {
+        fp_service.extensions = scan.clone();
+      }
+      handle.events.emit("extensions_reloaded", &scan);
+    }
+    _ => {}
+  }
+  handle.events.emit("folder_change", &change);
+}
+
 #[cfg(feature = "services")]
-async fn load_services(services_path: &Path) -> Result<Services, Box<dyn std::error::Error>> {
+async fn load_services(
+  services_path: &Path,
+  vars: &TemplateVars,
+) -> Result<Services, Box<dyn std::error::Error>> {
   println!("Services Path: {:?}", services_path.canonicalize()?);
-  let services = load_services_file(&services_path).await.unwrap();
-  Ok(services)
+  let services = load_services_file(services_path).await.unwrap();
+  Ok(services.expand(vars)?)
 }
 
 #[cfg(feature = "services")]
@@ -272,7 +875,7 @@ async fn handle_connection(
   peer_map: PeerMap,
   raw_stream: TcpStream,
   addr: SocketAddr,
-  registers: Arc<Mutex<WebsocketRegisters>>,
+  registers: Arc<WebsocketRegisters>,
 ) {
   use futures_util::{future, pin_mut, TryStreamExt};
   use serde_json::Value;
@@ -291,17 +894,31 @@ async fn handle_connection(
 
   let (outgoing, incoming) = ws_stream.split();
 
-  let broadcast_incoming = incoming.try_for_each(|msg| {
+  // Requests are read separately from being handled, so a disconnect is noticed while a request
+  // is still running and its database work can be cancelled. They're still handled in order.
+  let cancel = CancelToken::default();
+  let (req_tx, req_rx) = unbounded::<Message>();
+  let read_requests = {
+    let cancel = cancel.clone();
+    async move {
+      let _ = incoming
+        .try_for_each(|msg| {
+          let _ = req_tx.unbounded_send(msg);
+          future::ok(())
+        })
+        .await;
+      cancel.cancel();
+    }
+  };
+
+  let handle_requests = req_rx.for_each(|msg| {
     println!("{}: {:?}", &addr, msg.to_text().unwrap());
     // Create copies of the state locks to use inside async function
     let fp_service = fp_service.clone();
     let registers = registers.clone();
     let peer_map = peer_map.clone();
+    let cancel = cancel.clone();
     async move {
-      // Take control of registers and peer states
-      let registers = registers.lock().unwrap();
-      let peers = peer_map.lock().unwrap();
-
       // Deserialize incoming message
       let r: Result<Value, serde_json::Error> = serde_json::from_str(msg.to_text().unwrap());
       match r {
@@ -310,11 +927,14 @@ async fn handle_connection(
           let data = rec_msg["data"].clone();
 
           // Execute the registered function
-          let res = execute_register(registers, fp_service, op, data);
-          let res_msg =
-            Message::text(res.unwrap_or_else(|err| format!("{{ \"error\": \"{:?}\" }}", err)));
+          let res = execute_register(registers, fp_service, &cancel, op, data).await;
+          let res_msg = Message::text(
+            res.unwrap_or_else(|err| serde_json::json!({ "error": err.to_string() }).to_string()),
+          );
 
-          // Broadcast response only to ourselves
+          // Broadcast response only to ourselves. The peers are only locked now so other
+          // connections aren't held up while the request runs.
+          let peers = peer_map.lock().unwrap();
           let broadcast_recipients = peers
             .iter()
             .filter(|(peer_addr, _)| peer_addr == &&addr)
@@ -329,6 +949,7 @@ async fn handle_connection(
           let res_msg = Message::text("{ \"error\": \"error deserializing message\" }".to_string());
 
           // Broadcast response only to ourselves
+          let peers = peer_map.lock().unwrap();
           let broadcast_recipients = peers
             .iter()
             .filter(|(peer_addr, _)| peer_addr == &&addr)
@@ -339,28 +960,36 @@ async fn handle_connection(
           }
         }
       }
-
-      Ok(())
     }
   });
 
   let receive_from_others = rx.map(Ok).forward(outgoing);
 
-  pin_mut!(broadcast_incoming, receive_from_others);
-  future::select(broadcast_incoming, receive_from_others).await;
+  pin_mut!(read_requests, handle_requests, receive_from_others);
+  future::select(
+    future::join(read_requests, handle_requests),
+    receive_from_others,
+  )
+  .await;
+  cancel.cancel();
 
   println!("{} disconnected", &addr);
   peer_map.lock().unwrap().remove(&addr);
 }
 
 #[cfg(feature = "websocket")]
-fn execute_register(
-  registers: std::sync::MutexGuard<WebsocketRegisters>,
+async fn execute_register(
+  registers: Arc<WebsocketRegisters>,
   fp_service: Arc<Mutex<FlashpointService>>,
+  cancel: &CancelToken,
   op: &str,
   data: serde_json::Value,
 ) -> Result<String, Box<dyn std::error::Error>> {
-  use flashpoint_database::tag::InsertableTagCategory;
+  use flashpoint_database::game::InsertableAddApp;
+  use flashpoint_database::game_data::InsertableGameData;
+  use flashpoint_database::models::{AdditionalApp, Playlist};
+  use flashpoint_database::playlist::{InsertablePlaylist, PlaylistFilter};
+  use flashpoint_database::tag::{InsertableTag, InsertableTagCategory};
 
   let res_str: String;
   match op {
@@ -368,48 +997,509 @@ fn execute_register(
       println!("Init Data");
       ws_execute!(registers.init_data, res_str, fp_service);
     }
+    "update_prefs" => {
+      println!("Update Preferences");
+      ws_execute_blocking!(data, registers.update_prefs, res_str, fp_service, cancel);
+    }
     "view_all_games" => {
       println!("All Games");
-      ws_execute!(registers.view_all_games, res_str, fp_service);
+      ws_execute_db!(registers.view_all_games, res_str, fp_service, cancel);
     }
     "all_games" => {
       println!("All Games");
-      ws_execute!(registers.all_games, res_str, fp_service);
+      ws_execute_db!(registers.all_games, res_str, fp_service, cancel);
     }
     "all_tag_categories" => {
       println!("All Categories");
-      ws_execute!(registers.all_tag_categories, res_str, fp_service);
+      ws_execute_db!(registers.all_tag_categories, res_str, fp_service, cancel);
     }
     "create_tag_category" => {
       println!("Create Tag Category");
-      ws_execute!(
+      ws_execute_db!(
         &data,
         registers.create_tag_category,
         res_str,
         fp_service,
+        cancel,
         InsertableTagCategory
       );
     }
     "find_tag_by_name" => {
       println!("Find Tag By Name");
-      ws_execute!(
+      ws_execute_db!(
         &data,
         registers.find_tag_by_name,
         res_str,
         fp_service,
+        cancel,
+        String
+      );
+    }
+    "find_tags" => {
+      println!("Find Tags");
+      ws_execute_db!(registers.find_tags, res_str, fp_service, cancel);
+    }
+    "get_tag" => {
+      println!("Get Tag");
+      ws_execute_db!(&data, registers.get_tag, res_str, fp_service, cancel, i32);
+    }
+    "create_tag" => {
+      println!("Create Tag");
+      ws_execute_db!(
+        &data,
+        registers.create_tag,
+        res_str,
+        fp_service,
+        cancel,
+        InsertableTag
+      );
+    }
+    "delete_tag" => {
+      println!("Delete Tag");
+      ws_execute_db!(
+        &data,
+        registers.delete_tag,
+        res_str,
+        fp_service,
+        cancel,
+        i32
+      );
+    }
+    "save_tag" => {
+      println!("Save Tag");
+      ws_execute_db!(
+        &data,
+        registers.save_tag,
+        res_str,
+        fp_service,
+        cancel,
+        SaveTagRecv
+      );
+    }
+    "add_alias_to_tag" => {
+      println!("Add Alias To Tag");
+      ws_execute_db!(
+        &data,
+        registers.add_alias_to_tag,
+        res_str,
+        fp_service,
+        cancel,
+        AddTagAliasRecv
+      );
+    }
+    "save_tag_alias" => {
+      println!("Save Tag Alias");
+      ws_execute_db!(
+        &data,
+        registers.save_tag_alias,
+        res_str,
+        fp_service,
+        cancel,
+        SaveTagAliasRecv
+      );
+    }
+    "remove_tag_alias" => {
+      println!("Remove Tag Alias");
+      ws_execute_db!(
+        &data,
+        registers.remove_tag_alias,
+        res_str,
+        fp_service,
+        cancel,
+        i32
+      );
+    }
+    "set_primary_alias" => {
+      println!("Set Primary Alias");
+      ws_execute_db!(
+        &data,
+        registers.set_primary_alias,
+        res_str,
+        fp_service,
+        cancel,
+        SetPrimaryAliasRecv
+      );
+    }
+    "merge_tags" => {
+      println!("Merge Tags");
+      ws_execute_db!(
+        &data,
+        registers.merge_tags,
+        res_str,
+        fp_service,
+        cancel,
+        MergeTagsRecv
+      );
+    }
+    "find_tag_suggestions" => {
+      println!("Find Tag Suggestions");
+      ws_execute_db!(
+        &data,
+        registers.find_tag_suggestions,
+        res_str,
+        fp_service,
+        cancel,
+        TagSuggestionsRecv
+      );
+    }
+    "find_playlists" => {
+      println!("Find Playlists");
+      ws_execute_db!(
+        &data,
+        registers.find_playlists,
+        res_str,
+        fp_service,
+        cancel,
+        PlaylistFilter
+      );
+    }
+    "find_playlist" => {
+      println!("Find Playlist");
+      ws_execute_db!(
+        &data,
+        registers.find_playlist,
+        res_str,
+        fp_service,
+        cancel,
         String
       );
     }
+    "create_playlist" => {
+      println!("Create Playlist");
+      ws_execute_blocking!(
+        &data,
+        registers.create_playlist,
+        res_str,
+        fp_service,
+        cancel,
+        InsertablePlaylist
+      );
+    }
+    "update_playlist" => {
+      println!("Update Playlist");
+      ws_execute_blocking!(
+        &data,
+        registers.update_playlist,
+        res_str,
+        fp_service,
+        cancel,
+        Playlist
+      );
+    }
+    "remove_playlist" => {
+      println!("Remove Playlist");
+      ws_execute_blocking!(
+        &data,
+        registers.remove_playlist,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "find_playlist_games" => {
+      println!("Find Playlist Games");
+      ws_execute_db!(
+        &data,
+        registers.find_playlist_games,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "add_playlist_game" => {
+      println!("Add Playlist Game");
+      ws_execute_blocking!(
+        &data,
+        registers.add_playlist_game,
+        res_str,
+        fp_service,
+        cancel,
+        AddPlaylistGameRecv
+      );
+    }
+    "remove_playlist_game" => {
+      println!("Remove Playlist Game");
+      ws_execute_blocking!(
+        &data,
+        registers.remove_playlist_game,
+        res_str,
+        fp_service,
+        cancel,
+        PlaylistGameRecv
+      );
+    }
+    "update_playlist_game" => {
+      println!("Update Playlist Game");
+      ws_execute_blocking!(
+        &data,
+        registers.update_playlist_game,
+        res_str,
+        fp_service,
+        cancel,
+        UpdatePlaylistGameRecv
+      );
+    }
+    "move_playlist_game" => {
+      println!("Move Playlist Game");
+      ws_execute_blocking!(
+        &data,
+        registers.move_playlist_game,
+        res_str,
+        fp_service,
+        cancel,
+        MovePlaylistGameRecv
+      );
+    }
+    "update_playlist_games" => {
+      println!("Update Playlist Games");
+      ws_execute_blocking!(
+        &data,
+        registers.update_playlist_games,
+        res_str,
+        fp_service,
+        cancel,
+        UpdatePlaylistGamesRecv
+      );
+    }
+    "playlist_sync_report" => {
+      println!("Playlist Sync Report");
+      ws_execute!(registers.playlist_sync_report, res_str, fp_service);
+    }
     "find_games_with_tag" => {
       println!("Find Games With Tag");
-      ws_execute!(
+      ws_execute_db!(
         &data,
         registers.find_games_with_tag,
         res_str,
         fp_service,
+        cancel,
+        String
+      );
+    }
+    "find_add_app" => {
+      println!("Find Add App");
+      ws_execute_db!(
+        &data,
+        registers.find_add_app,
+        res_str,
+        fp_service,
+        cancel,
         String
       );
     }
+    "find_add_apps" => {
+      println!("Find Add Apps");
+      ws_execute_db!(
+        &data,
+        registers.find_add_apps,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "create_add_app" => {
+      println!("Create Add App");
+      ws_execute_db!(
+        &data,
+        registers.create_add_app,
+        res_str,
+        fp_service,
+        cancel,
+        InsertableAddApp
+      );
+    }
+    "save_add_app" => {
+      println!("Save Add App");
+      ws_execute_db!(
+        &data,
+        registers.save_add_app,
+        res_str,
+        fp_service,
+        cancel,
+        AdditionalApp
+      );
+    }
+    "remove_add_app" => {
+      println!("Remove Add App");
+      ws_execute_db!(
+        &data,
+        registers.remove_add_app,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "find_game_data" => {
+      println!("Find Game Data");
+      ws_execute_db!(
+        &data,
+        registers.find_game_data,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "import_game_data" => {
+      println!("Import Game Data");
+      ws_execute_db!(
+        &data,
+        registers.import_game_data,
+        res_str,
+        fp_service,
+        cancel,
+        InsertableGameData
+      );
+    }
+    "set_active_game_data" => {
+      println!("Set Active Game Data");
+      ws_execute_db!(
+        &data,
+        registers.set_active_game_data,
+        res_str,
+        fp_service,
+        cancel,
+        SetActiveGameDataRecv
+      );
+    }
+    "set_game_data_present" => {
+      println!("Set Game Data Present");
+      ws_execute_db!(
+        &data,
+        registers.set_game_data_present,
+        res_str,
+        fp_service,
+        cancel,
+        SetGameDataPresentRecv
+      );
+    }
+    "scan_data_packs" => {
+      println!("Scan Data Packs");
+      ws_execute_blocking!(
+        &data,
+        registers.scan_data_packs,
+        res_str,
+        fp_service,
+        cancel,
+        DataPackScanRecv
+      );
+    }
+    "find_sources" => {
+      println!("Find Sources");
+      ws_execute_db!(registers.find_sources, res_str, fp_service, cancel);
+    }
+    "register_source" => {
+      println!("Register Source");
+      ws_execute_blocking!(
+        &data,
+        registers.register_source,
+        res_str,
+        fp_service,
+        cancel,
+        RegisterSourceRecv
+      );
+    }
+    "remove_source" => {
+      println!("Remove Source");
+      ws_execute_db!(
+        &data,
+        registers.remove_source,
+        res_str,
+        fp_service,
+        cancel,
+        i32
+      );
+    }
+    "find_source_urls" => {
+      println!("Find Source Urls");
+      ws_execute_db!(
+        &data,
+        registers.find_source_urls,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "search_games" => {
+      println!("Search Games");
+      ws_execute_db!(
+        &data,
+        registers.search_games,
+        res_str,
+        fp_service,
+        cancel,
+        SearchGamesRecv
+      );
+    }
+    "find_random_games" => {
+      println!("Find Random Games");
+      ws_execute_db!(
+        &data,
+        registers.find_random_games,
+        res_str,
+        fp_service,
+        cancel,
+        RandomGamesRecv
+      );
+    }
+    "find_unique_values" => {
+      println!("Find Unique Values");
+      ws_execute_db!(
+        &data,
+        registers.find_unique_values,
+        res_str,
+        fp_service,
+        cancel,
+        UniqueValuesRecv
+      );
+    }
+    "find_unique_values_in_order" => {
+      println!("Find Unique Values In Order");
+      ws_execute_db!(
+        &data,
+        registers.find_unique_values_in_order,
+        res_str,
+        fp_service,
+        cancel,
+        UniqueValuesRecv
+      );
+    }
+    "find_platforms" => {
+      println!("Find Platforms");
+      ws_execute_db!(
+        &data,
+        registers.find_platforms,
+        res_str,
+        fp_service,
+        cancel,
+        FindPlatformsRecv
+      );
+    }
+    #[cfg(feature = "services")]
+    "switch_server" => {
+      println!("Switch Server");
+      ws_execute!(&data, registers.switch_server, res_str, fp_service, String);
+    }
+    "launch_game" => {
+      println!("Launch Game");
+      ws_execute_blocking!(
+        &data,
+        registers.launch_game,
+        res_str,
+        fp_service,
+        cancel,
+        String
+      );
+    }
+    "parse_search" => {
+      println!("Parse Search");
+      ws_execute!(&data, registers.parse_search, res_str, fp_service, String);
+    }
     "add" => {
       println!("Add");
       ws_execute!(&data, registers.add, res_str, fp_service, AddRecv);
@@ -420,3 +1510,35 @@ fn execute_register(
   };
   Ok(res_str)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn saving_prefs_keeps_unknown_fields() {
+    let dir = std::env::temp_dir().join(format!("fp_prefs_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("preferences.json");
+    std::fs::write(&path, r#"{ "legacyOnly": 1, "themeFolderPath": "Themes" }"#).unwrap();
+    let prefs: Preferences = serde_json::from_str("{}").unwrap();
+
+    let saved = save_prefs(
+      &dir,
+      &prefs,
+      serde_json::json!({ "themeFolderPath": "MyThemes" }),
+    )
+    .unwrap();
+    let file: serde_json::Value =
+      serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let leftovers = std::fs::read_dir(&dir).unwrap().count();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(saved.theme_folder_path, "MyThemes");
+    assert_eq!(file["themeFolderPath"], "MyThemes");
+    assert_eq!(file["legacyOnly"], 1);
+    // The temporary file was renamed over the old one.
+    assert_eq!(leftovers, 1);
+  }
+}
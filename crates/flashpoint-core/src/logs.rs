@@ -0,0 +1,99 @@
+//! The backend's log. Entries are kept in memory and pushed to clients as `log` events.
+
+use crate::events::EventSender;
+use flashpoint_config::types::LogLevel;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Child;
+
+/// Oldest entries are dropped past this.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LogEntry {
+  pub source: String,
+  pub content: String,
+  /// Milliseconds since the Unix epoch.
+  pub timestamp: i64,
+  pub log_level: LogLevel,
+}
+
+#[derive(Clone, Default)]
+pub struct Logger {
+  entries: Arc<Mutex<VecDeque<LogEntry>>>,
+  events: EventSender,
+}
+
+impl Logger {
+  /// Makes a logger sharing this one's entries, but emitting events through `events`.
+  pub fn with_events(&self, events: EventSender) -> Self {
+    Self {
+      entries: self.entries.clone(),
+      events,
+    }
+  }
+
+  pub fn log(&self, log_level: LogLevel, source: &str, content: &str) {
+    println!("[{}] {}", source, content);
+    let entry = LogEntry {
+      source: source.to_string(),
+      content: content.to_string(),
+      timestamp: chrono::Utc::now().timestamp_millis(),
+      log_level,
+    };
+    self.events.emit("log", &entry);
+    let mut entries = self.entries.lock().unwrap();
+    if entries.len() == MAX_ENTRIES {
+      entries.pop_front();
+    }
+    entries.push_back(entry);
+  }
+
+  pub fn info(&self, source: &str, content: &str) {
+    self.log(LogLevel::Info, source, content);
+  }
+
+  pub fn error(&self, source: &str, content: &str) {
+    self.log(LogLevel::Error, source, content);
+  }
+
+  /// Logs every line the child writes, stdout as info and stderr as errors. The child must have
+  /// been spawned with piped output, from within a tokio runtime.
+  pub fn capture_output(&self, child: &mut Child, source: &str) {
+    if let Some(stdout) = child.stdout.take() {
+      tokio::spawn(log_lines(
+        stdout,
+        self.clone(),
+        source.to_string(),
+        LogLevel::Info,
+      ));
+    }
+    if let Some(stderr) = child.stderr.take() {
+      tokio::spawn(log_lines(
+        stderr,
+        self.clone(),
+        source.to_string(),
+        LogLevel::Error,
+      ));
+    }
+  }
+
+  pub fn entries(&self) -> Vec<LogEntry> {
+    self.entries.lock().unwrap().iter().cloned().collect()
+  }
+}
+
+async fn log_lines(
+  reader: impl AsyncRead + Unpin,
+  logger: Logger,
+  source: String,
+  log_level: LogLevel,
+) {
+  let mut lines = BufReader::new(reader).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    logger.log(log_level.clone(), &source, &line);
+  }
+}
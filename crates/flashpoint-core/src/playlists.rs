@@ -0,0 +1,428 @@
+//! Keeps the `playlist` tables in sync with the legacy launcher's JSON playlist files.
+//!
+//! Files are the source of truth on startup: every file is imported into the database. After that,
+//! any change made through the database is written back to its file. A file that was modified on
+//! disk since we last read or wrote it is reported as a conflict instead of being overwritten.
+
+use flashpoint_database::{models::Playlist, playlist, types::DbState};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistFile {
+  pub id: String,
+  pub title: String,
+  #[serde(default)]
+  pub description: String,
+  #[serde(default)]
+  pub author: String,
+  #[serde(default)]
+  pub icon: Option<String>,
+  #[serde(default)]
+  pub library: String,
+  #[serde(default)]
+  pub extreme: bool,
+  #[serde(default)]
+  pub games: Vec<PlaylistFileGame>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistFileGame {
+  #[serde(default)]
+  pub order: i32,
+  #[serde(default)]
+  pub notes: String,
+  pub game_id: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PlaylistFileError {
+  pub path: String,
+  pub error: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistSyncReport {
+  /// Playlists that were not in the database yet.
+  pub imported: usize,
+  /// Playlists whose database copy was overwritten by their file.
+  pub updated: usize,
+  pub unchanged: usize,
+  /// Playlists removed from the database because their file was deleted.
+  pub removed: usize,
+  /// Files that could not be read or parsed.
+  pub errors: Vec<PlaylistFileError>,
+  /// Files that changed on disk while the database copy also changed.
+  pub conflicts: Vec<String>,
+}
+
+pub struct PlaylistSync {
+  folder: PathBuf,
+  /// Playlist ID -> (file path, file modification time when we last read or wrote it).
+  synced: HashMap<String, (PathBuf, SystemTime)>,
+  /// File path -> (playlist ID, modification time), the same entries looked up by path.
+  synced_paths: HashMap<PathBuf, (String, SystemTime)>,
+  pub report: PlaylistSyncReport,
+}
+
+impl PlaylistSync {
+  pub fn new(folder: PathBuf) -> Self {
+    Self {
+      folder,
+      synced: HashMap::new(),
+      synced_paths: HashMap::new(),
+      report: PlaylistSyncReport::default(),
+    }
+  }
+
+  pub fn folder(&self) -> &Path {
+    &self.folder
+  }
+
+  /// Imports every `.json` file in the playlist folder into the database.
+  /// Files that haven't changed since the last import are skipped.
+  pub fn import_all(&mut self, db: &DbState) -> &PlaylistSyncReport {
+    self.report = PlaylistSyncReport::default();
+    let entries = match fs::read_dir(&self.folder) {
+      Ok(entries) => entries,
+      // No playlist folder means no playlists.
+      Err(_) => return &self.report,
+    };
+    for entry in entries.flatten() {
+      let path = entry.path();
+      if path.extension().and_then(|e| e.to_str()) != Some("json") {
+        continue;
+      }
+      if let Err(e) = self.import_file(db, &path) {
+        self.report.errors.push(PlaylistFileError {
+          path: path.to_string_lossy().to_string(),
+          error: e.to_string(),
+        });
+      }
+    }
+    &self.report
+  }
+
+  /// Brings the database in line with the given files, which changed on disk. Files we wrote
+  /// ourselves are skipped, and deleted files have their playlist removed from the database.
+  pub fn apply_changes(&mut self, db: &DbState, paths: &[PathBuf]) -> &PlaylistSyncReport {
+    self.report = PlaylistSyncReport::default();
+    for path in paths {
+      if path.extension().and_then(|e| e.to_str()) != Some("json") {
+        continue;
+      }
+      let res = if path.exists() {
+        self.import_file(db, path)
+      } else {
+        self.remove_deleted(db, path)
+      };
+      if let Err(e) = res {
+        self.report.errors.push(PlaylistFileError {
+          path: path.to_string_lossy().to_string(),
+          error: e.to_string(),
+        });
+      }
+    }
+    &self.report
+  }
+
+  fn import_file(&mut self, db: &DbState, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let modified = fs::metadata(path)?.modified()?;
+    if self.synced_paths.get(path).map(|(_, m)| m) == Some(&modified) {
+      self.report.unchanged += 1;
+      return Ok(());
+    }
+
+    let mut file: PlaylistFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+    file.games.sort_by_key(|g| g.order);
+    for (i, g) in file.games.iter_mut().enumerate() {
+      g.order = i as i32;
+    }
+
+    match load_playlist_file(db, &file.id) {
+      Ok(existing) if existing == file => self.report.unchanged += 1,
+      Ok(_) => {
+        save_playlist_file(db, file.clone())?;
+        self.report.updated += 1;
+      }
+      Err(_) => {
+        save_playlist_file(db, file.clone())?;
+        self.report.imported += 1;
+      }
+    }
+    self.mark_synced(file.id, path.to_path_buf(), modified);
+    Ok(())
+  }
+
+  /// Writes the database copy of a playlist back to its file.
+  pub fn export(
+    &mut self,
+    db: &DbState,
+    playlist_id: &str,
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    let file = load_playlist_file(db, playlist_id)?;
+    let path = match self.synced.get(playlist_id).cloned() {
+      Some((path, modified)) => {
+        self.check_conflict(&path, modified)?;
+        path
+      }
+      None => self.new_file_path(&file),
+    };
+    fs::create_dir_all(&self.folder)?;
+    fs::write(&path, serde_json::to_string_pretty(&file)?)?;
+    let modified = fs::metadata(&path)?.modified()?;
+    self.mark_synced(playlist_id.to_string(), path, modified);
+    Ok(())
+  }
+
+  /// Deletes the file of a playlist that was removed from the database.
+  pub fn remove(&mut self, playlist_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some((path, modified)) = self.synced.get(playlist_id).cloned() {
+      self.check_conflict(&path, modified)?;
+      if path.exists() {
+        fs::remove_file(&path)?;
+      }
+      self.forget(playlist_id);
+    }
+    Ok(())
+  }
+
+  fn remove_deleted(
+    &mut self,
+    db: &DbState,
+    path: &Path,
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    // Files we never synced have no playlist to remove.
+    if let Some((playlist_id, _)) = self.synced_paths.get(path).cloned() {
+      playlist::remove_playlist(db, playlist_id.clone())?;
+      self.forget(&playlist_id);
+      self.report.removed += 1;
+    }
+    Ok(())
+  }
+
+  fn mark_synced(&mut self, playlist_id: String, path: PathBuf, modified: SystemTime) {
+    self.forget(&playlist_id);
+    self
+      .synced_paths
+      .insert(path.clone(), (playlist_id.clone(), modified));
+    self.synced.insert(playlist_id, (path, modified));
+  }
+
+  fn forget(&mut self, playlist_id: &str) {
+    if let Some((path, _)) = self.synced.remove(playlist_id) {
+      self.synced_paths.remove(&path);
+    }
+  }
+
+  fn check_conflict(
+    &mut self,
+    path: &Path,
+    last_synced: SystemTime,
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    if let Ok(current) = fs::metadata(path).and_then(|m| m.modified()) {
+      if current != last_synced {
+        let path_str = path.to_string_lossy().to_string();
+        if !self.report.conflicts.contains(&path_str) {
+          self.report.conflicts.push(path_str.clone());
+        }
+        return Err(format!("playlist file {} was modified on disk", path_str).into());
+      }
+    }
+    Ok(())
+  }
+
+  fn new_file_path(&self, file: &PlaylistFile) -> PathBuf {
+    let by_title = self.folder.join(sanitize_filename(&file.title) + ".json");
+    if by_title.exists() {
+      self.folder.join(sanitize_filename(&file.id) + ".json")
+    } else {
+      by_title
+    }
+  }
+}
+
+fn load_playlist_file(
+  db: &DbState,
+  playlist_id: &str,
+) -> Result<PlaylistFile, Box<dyn std::error::Error>> {
+  let p = playlist::find_playlist(db, playlist_id.to_string())?;
+  let games = playlist::find_playlist_games(db, playlist_id.to_string())?
+    .into_iter()
+    .filter_map(|g| {
+      Some(PlaylistFileGame {
+        order: g.order,
+        notes: g.notes,
+        game_id: g.game_id?,
+      })
+    })
+    .collect();
+  Ok(PlaylistFile {
+    id: p.id,
+    title: p.title,
+    description: p.description,
+    author: p.author,
+    icon: p.icon,
+    library: p.library,
+    extreme: p.extreme,
+    games,
+  })
+}
+
+fn save_playlist_file(db: &DbState, file: PlaylistFile) -> Result<(), Box<dyn std::error::Error>> {
+  let games = file
+    .games
+    .into_iter()
+    .map(|g| (g.game_id, g.notes))
+    .collect();
+  playlist::replace_playlist(
+    db,
+    Playlist {
+      id: file.id,
+      title: file.title,
+      description: file.description,
+      author: file.author,
+      icon: file.icon,
+      library: file.library,
+      extreme: file.extreme,
+    },
+    games,
+  )?;
+  Ok(())
+}
+
+fn sanitize_filename(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| match c {
+      '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+      c if c.is_control() => '_',
+      c => c,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flashpoint_database::{game::save_game, test_game, test_state};
+  use std::time::Duration;
+
+  /// A fresh playlist folder for `test`.
+  fn folder(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fp_playlists_{}_{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  const LEGACY_FILE: &str = r#"{
+    "id": "p1",
+    "title": "Favourites",
+    "library": "arcade",
+    "games": [
+      { "gameId": "b", "order": 5, "notes": "second" },
+      { "gameId": "a", "order": 1 }
+    ]
+  }"#;
+
+  #[test]
+  fn imports_and_exports_legacy_files() {
+    let dir = folder("round_trip");
+    let db = test_state();
+    save_game(&db, test_game("a", "A")).unwrap();
+    save_game(&db, test_game("b", "B")).unwrap();
+    fs::write(dir.join("favourites.json"), LEGACY_FILE).unwrap();
+
+    let mut sync = PlaylistSync::new(dir.clone());
+    assert_eq!(sync.import_all(&db).imported, 1);
+    let games = playlist::find_playlist_games(&db, "p1".to_string()).unwrap();
+    let ids: Vec<_> = games.iter().map(|g| g.game_id.clone().unwrap()).collect();
+    assert_eq!(ids, vec!["a", "b"]);
+    // Untouched files are skipped on the next import.
+    assert_eq!(sync.import_all(&db).unchanged, 1);
+
+    playlist::remove_playlist_game(&db, "p1".to_string(), "a".to_string()).unwrap();
+    sync.export(&db, "p1").unwrap();
+    let file: PlaylistFile =
+      serde_json::from_str(&fs::read_to_string(dir.join("favourites.json")).unwrap()).unwrap();
+    assert_eq!(file.title, "Favourites");
+    assert_eq!(file.games.len(), 1);
+    assert_eq!(file.games[0].game_id, "b");
+    assert_eq!(file.games[0].notes, "second");
+    // Our own write doesn't count as a change.
+    assert_eq!(sync.import_all(&db).unchanged, 1);
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn files_changed_on_disk_are_conflicts() {
+    let dir = folder("conflict");
+    let db = test_state();
+    save_game(&db, test_game("a", "A")).unwrap();
+    save_game(&db, test_game("b", "B")).unwrap();
+    let path = dir.join("favourites.json");
+    fs::write(&path, LEGACY_FILE).unwrap();
+    let mut sync = PlaylistSync::new(dir.clone());
+    assert_eq!(sync.import_all(&db).imported, 1);
+
+    let file = fs::File::options().write(true).open(&path).unwrap();
+    file
+      .set_modified(SystemTime::now() + Duration::from_secs(60))
+      .unwrap();
+    assert!(sync.export(&db, "p1").is_err());
+    assert!(sync.remove("p1").is_err());
+    assert_eq!(
+      sync.report.conflicts,
+      vec![path.to_string_lossy().to_string()]
+    );
+    // The file on disk is left alone.
+    assert_eq!(fs::read_to_string(&path).unwrap(), LEGACY_FILE);
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn malformed_files_are_reported() {
+    let dir = folder("malformed");
+    let db = test_state();
+    fs::write(dir.join("broken.json"), "{ \"id\": ").unwrap();
+    fs::write(dir.join("notes.txt"), "not a playlist").unwrap();
+    let mut sync = PlaylistSync::new(dir.clone());
+    let report = sync.import_all(&db);
+    assert_eq!(report.imported, 0);
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].path.ends_with("broken.json"));
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn changed_files_are_applied() {
+    let dir = folder("changes");
+    let db = test_state();
+    save_game(&db, test_game("a", "A")).unwrap();
+    save_game(&db, test_game("b", "B")).unwrap();
+    let path = dir.join("favourites.json");
+    fs::write(&path, LEGACY_FILE).unwrap();
+    let changed = [path.clone()];
+    let mut sync = PlaylistSync::new(dir.clone());
+    assert_eq!(sync.apply_changes(&db, &changed).imported, 1);
+
+    // The watcher sees our own exports too, they are skipped.
+    playlist::remove_playlist_game(&db, "p1".to_string(), "a".to_string()).unwrap();
+    sync.export(&db, "p1").unwrap();
+    assert_eq!(sync.apply_changes(&db, &changed).unchanged, 1);
+
+    fs::remove_file(&path).unwrap();
+    assert_eq!(sync.apply_changes(&db, &changed).removed, 1);
+    assert!(playlist::find_playlist(&db, "p1".to_string()).is_err());
+    // Nothing left to remove.
+    assert_eq!(sync.apply_changes(&db, &changed).removed, 0);
+    let _ = fs::remove_dir_all(&dir);
+  }
+}
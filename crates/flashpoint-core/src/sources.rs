@@ -0,0 +1,67 @@
+//! Reads source index files, which list the data packs a source (a download server, or a mirror on
+//! disk or on the LAN) can provide.
+//!
+//! An index is a text file with one data pack per line: its sha256, whitespace, then its path
+//! relative to the source's base URL. Blank lines and lines starting with `#` are ignored.
+
+use flashpoint_database::source::{self, InsertableSource, RegisterSourceSummary};
+use flashpoint_database::types::DbState;
+use std::fs;
+use std::path::Path;
+
+/// Parses a source index into (sha256, url path) pairs.
+pub fn parse_source_index(contents: &str) -> Result<Vec<(String, String)>, String> {
+  let mut entries = vec![];
+  for (i, line) in contents.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let (sha256, url_path) = line
+      .split_once(char::is_whitespace)
+      .map(|(h, p)| (h, p.trim()))
+      .ok_or_else(|| format!("line {}: expected a sha256 and a path", i + 1))?;
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(format!("line {}: invalid sha256 {}", i + 1, sha256));
+    }
+    entries.push((sha256.to_string(), url_path.to_string()));
+  }
+  Ok(entries)
+}
+
+/// Reads a source index file and registers (or refreshes) the source it describes.
+pub fn register_source_file(
+  db: &DbState,
+  name: String,
+  base_url: String,
+  index_path: &Path,
+) -> Result<RegisterSourceSummary, Box<dyn std::error::Error>> {
+  let entries = parse_source_index(&fs::read_to_string(index_path)?)?;
+  let source_file_url = index_path.canonicalize()?.to_string_lossy().to_string();
+  Ok(source::register_source(
+    db,
+    InsertableSource {
+      name,
+      source_file_url,
+      base_url,
+    },
+    entries,
+  )?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_index_lines() {
+    let hash = "a".repeat(64);
+    let index = format!("# LAN mirror\n\n{}  games/a b.zip \n", hash);
+    assert_eq!(
+      parse_source_index(&index).unwrap(),
+      vec![(hash, "games/a b.zip".to_string())]
+    );
+    assert!(parse_source_index("nothex games/a.zip").is_err());
+    assert!(parse_source_index(&"b".repeat(64)).is_err());
+  }
+}
@@ -0,0 +1,621 @@
+//! Runs the background processes listed in services.json: `start` entries once, the server and
+//! daemons kept alive, and `stop` entries on exit. Their output goes to the log, except for
+//! processes left running on exit, which write to a file in `Logs` instead. Every state change is
+//! sent to clients as a `service_change` event.
+
+use crate::events::EventSender;
+use crate::logs::Logger;
+use flashpoint_config::types::{BackProcessInfo, Services};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Where processes left running on exit write their output, relative to the Flashpoint folder.
+const DETACHED_LOG_FOLDER: &str = "Logs";
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ServiceKind {
+  Server,
+  Daemon,
+  Start,
+  Stop,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ServiceState {
+  Running,
+  /// Exited on its own. The server and daemons are restarted after a delay.
+  Exited,
+  /// Couldn't be started.
+  Failed,
+  /// Killed on exit as asked for by `kill`, or because another server replaced it.
+  Killed,
+  /// Left running on exit.
+  Detached,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStatus {
+  pub name: String,
+  pub kind: ServiceKind,
+  pub state: ServiceState,
+  pub pid: Option<u32>,
+  pub exit_code: Option<i32>,
+  pub restarts: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct SupervisorOptions {
+  /// Delay before the first restart of a crashed process. Doubles on every crash after that.
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+  /// A process that ran at least this long before exiting is restarted with the initial delay.
+  pub stable_after: Duration,
+}
+
+impl Default for SupervisorOptions {
+  fn default() -> Self {
+    Self {
+      initial_backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(60),
+      stable_after: Duration::from_secs(30),
+    }
+  }
+}
+
+/// The server named in the config isn't one of the servers in services.json.
+#[derive(Debug)]
+pub struct UnknownServer {
+  pub name: String,
+  pub available: Vec<String>,
+}
+
+impl std::fmt::Display for UnknownServer {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "unknown server \"{}\", available servers: {}",
+      self.name,
+      self.available.join(", ")
+    )
+  }
+}
+
+impl std::error::Error for UnknownServer {}
+
+/// Finds the server called `name` in services.json.
+pub fn find_server<'a>(
+  services: &'a Services,
+  name: &str,
+) -> Result<&'a BackProcessInfo, UnknownServer> {
+  services
+    .server
+    .iter()
+    .find(|s| s.name == name)
+    .ok_or_else(|| UnknownServer {
+      name: name.to_string(),
+      available: services.server.iter().map(|s| s.name.clone()).collect(),
+    })
+}
+
+/// Why a process task was told to stop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StopReason {
+  /// The backend is exiting. Processes are only killed if they ask for it with `kill`.
+  Exit,
+  /// Another server is taking this one's place, so it's always killed.
+  Replaced,
+}
+
+pub struct ServiceSupervisor {
+  shared: Arc<Shared>,
+  shutdown: watch::Sender<Option<StopReason>>,
+  tasks: Mutex<Vec<JoinHandle<()>>>,
+  server: Mutex<Option<ActiveServer>>,
+}
+
+struct ActiveServer {
+  name: String,
+  stop: watch::Sender<Option<StopReason>>,
+  task: JoinHandle<()>,
+}
+
+struct Shared {
+  fp_path: PathBuf,
+  opts: SupervisorOptions,
+  events: EventSender,
+  logger: Logger,
+  statuses: Mutex<Vec<ServiceStatus>>,
+}
+
+impl ServiceSupervisor {
+  /// Process paths in services.json are relative to `fp_path`.
+  pub fn new(
+    fp_path: PathBuf,
+    events: EventSender,
+    logger: Logger,
+    opts: SupervisorOptions,
+  ) -> Self {
+    Self {
+      shared: Arc::new(Shared {
+        fp_path,
+        opts,
+        events,
+        logger,
+        statuses: Mutex::new(vec![]),
+      }),
+      shutdown: watch::channel(None).0,
+      tasks: Mutex::new(vec![]),
+      server: Mutex::new(None),
+    }
+  }
+
+  /// Runs the `start` entries, then the server (if any) and the daemons. Must be called from
+  /// within a tokio runtime.
+  pub fn start(&self, services: &Services, server: Option<&BackProcessInfo>) {
+    let mut tasks = self.tasks.lock().unwrap();
+    for info in &services.start {
+      tasks.push(self.spawn(info, ServiceKind::Start, false));
+    }
+    if let Some(info) = server {
+      self.set_server(info);
+    }
+    for info in &services.daemon {
+      tasks.push(self.spawn(info, ServiceKind::Daemon, true));
+    }
+  }
+
+  /// Stops supervising, kills the processes marked with `kill`, then runs the `stop` entries one
+  /// after the other.
+  pub async fn stop(&self, services: &Services) {
+    let _ = self.shutdown.send(Some(StopReason::Exit));
+    let server = self.server.lock().unwrap().take();
+    if let Some(server) = server {
+      let _ = server.stop.send(Some(StopReason::Exit));
+      let _ = server.task.await;
+    }
+    let tasks: Vec<_> = self.tasks.lock().unwrap().drain(..).collect();
+    for task in tasks {
+      let _ = task.await;
+    }
+    for info in &services.stop {
+      // A fresh receiver, so the stop entries aren't cut short by the shutdown itself.
+      let (_tx, rx) = watch::channel(None);
+      run_process(
+        self.shared.clone(),
+        info.clone(),
+        ServiceKind::Stop,
+        false,
+        rx,
+      )
+      .await;
+    }
+  }
+
+  /// Kills the running server, if any, and starts `info` in its place once the old one is gone.
+  /// Must be called from within a tokio runtime.
+  pub fn set_server(&self, info: &BackProcessInfo) {
+    let mut server = self.server.lock().unwrap();
+    let previous = server.take();
+    if let Some(previous) = &previous {
+      let _ = previous.stop.send(Some(StopReason::Replaced));
+    }
+    let (stop, rx) = watch::channel(None);
+    let shared = self.shared.clone();
+    let next = info.clone();
+    let task = tokio::spawn(async move {
+      if let Some(previous) = previous {
+        let _ = previous.task.await;
+      }
+      run_process(shared, next, ServiceKind::Server, true, rx).await;
+    });
+    *server = Some(ActiveServer {
+      name: info.name.clone(),
+      stop,
+      task,
+    });
+  }
+
+  /// The name of the server started last.
+  pub fn server_name(&self) -> Option<String> {
+    self.server.lock().unwrap().as_ref().map(|s| s.name.clone())
+  }
+
+  pub fn statuses(&self) -> Vec<ServiceStatus> {
+    self.shared.statuses.lock().unwrap().clone()
+  }
+
+  fn spawn(&self, info: &BackProcessInfo, kind: ServiceKind, supervise: bool) -> JoinHandle<()> {
+    tokio::spawn(run_process(
+      self.shared.clone(),
+      info.clone(),
+      kind,
+      supervise,
+      self.shutdown.subscribe(),
+    ))
+  }
+}
+
+fn display_name(info: &BackProcessInfo) -> &str {
+  if info.name.is_empty() {
+    &info.filename
+  } else {
+    &info.name
+  }
+}
+
+/// Runs a process until it exits, or keeps restarting it if `supervise` is set, until shutdown.
+async fn run_process(
+  shared: Arc<Shared>,
+  info: BackProcessInfo,
+  kind: ServiceKind,
+  supervise: bool,
+  mut shutdown: watch::Receiver<Option<StopReason>>,
+) {
+  let name = display_name(&info).to_string();
+  let mut backoff = shared.opts.initial_backoff;
+  let mut restarts = 0;
+  loop {
+    if shutdown.borrow().is_some() {
+      return;
+    }
+    let started = Instant::now();
+    match spawn_child(&shared, &info, &name) {
+      Ok(mut child) => {
+        shared.set_status(
+          &name,
+          kind,
+          ServiceState::Running,
+          child.id(),
+          None,
+          restarts,
+        );
+        tokio::select! {
+          status = child.wait() => {
+            let exit_code = status.ok().and_then(|s| s.code());
+            shared.set_status(&name, kind, ServiceState::Exited, None, exit_code, restarts);
+            let code = exit_code.map_or("none".to_string(), |c| c.to_string());
+            shared.logger.info(&name, &format!("Exited with code {}", code));
+          }
+          _ = shutdown.changed() => {
+            if info.kill || *shutdown.borrow() == Some(StopReason::Replaced) {
+              let _ = child.kill().await;
+              shared.set_status(&name, kind, ServiceState::Killed, None, None, restarts);
+            } else {
+              shared.set_status(&name, kind, ServiceState::Detached, child.id(), None, restarts);
+            }
+            return;
+          }
+        }
+      }
+      Err(e) => {
+        shared.set_status(&name, kind, ServiceState::Failed, None, None, restarts);
+        shared
+          .logger
+          .error(&name, &format!("Failed to start: {}", e));
+      }
+    }
+    if !supervise {
+      return;
+    }
+
+    if started.elapsed() >= shared.opts.stable_after {
+      backoff = shared.opts.initial_backoff;
+    }
+    shared.logger.info(
+      &name,
+      &format!("Restarting in {:.1}s", backoff.as_secs_f32()),
+    );
+    tokio::select! {
+      _ = tokio::time::sleep(backoff) => {}
+      _ = shutdown.changed() => return,
+    }
+    backoff = (backoff * 2).min(shared.opts.max_backoff);
+    restarts += 1;
+  }
+}
+
+fn spawn_child(shared: &Shared, info: &BackProcessInfo, name: &str) -> std::io::Result<Child> {
+  let cwd = shared.fp_path.join(&info.path);
+  // Use the file next to the working directory when there is one, otherwise look it up on PATH.
+  let local = cwd.join(&info.filename);
+  let program = if local.is_file() {
+    local
+  } else {
+    PathBuf::from(&info.filename)
+  };
+  let mut command = Command::new(program);
+  command
+    .args(&info.arguments)
+    .current_dir(cwd)
+    .stdin(Stdio::null());
+  if info.kill {
+    let mut child = command
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()?;
+    shared.logger.capture_output(&mut child, name);
+    return Ok(child);
+  }
+
+  // Pipes to us would close when the backend exits, and the process's next write would kill it.
+  let log_folder = shared.fp_path.join(DETACHED_LOG_FOLDER);
+  fs::create_dir_all(&log_folder)?;
+  let log_path = log_folder.join(format!("{}.log", log_file_name(name)));
+  let log = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(&log_path)?;
+  let child = command.stdout(log.try_clone()?).stderr(log).spawn()?;
+  shared
+    .logger
+    .info(name, &format!("Output goes to {}", log_path.display()));
+  Ok(child)
+}
+
+/// `name` with anything that can't be in a file name replaced.
+fn log_file_name(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| match c {
+      '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+      c => c,
+    })
+    .collect()
+}
+
+impl Shared {
+  fn set_status(
+    &self,
+    name: &str,
+    kind: ServiceKind,
+    state: ServiceState,
+    pid: Option<u32>,
+    exit_code: Option<i32>,
+    restarts: u32,
+  ) {
+    let status = ServiceStatus {
+      name: name.to_string(),
+      kind,
+      state,
+      pid,
+      exit_code,
+      restarts,
+    };
+    self.events.emit("service_change", &status);
+    let mut statuses = self.statuses.lock().unwrap();
+    match statuses
+      .iter_mut()
+      .find(|s| s.name == name && s.kind == kind)
+    {
+      Some(s) => *s = status,
+      None => statuses.push(status),
+    }
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use flashpoint_config::types::LogLevel;
+  use futures_channel::mpsc::unbounded;
+  use futures_util::StreamExt;
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::Path;
+
+  fn script(dir: &Path, name: &str, body: &str) -> BackProcessInfo {
+    let path = dir.join(name);
+    std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    BackProcessInfo {
+      name: name.trim_end_matches(".sh").to_string(),
+      mad4fp: false,
+      path: "scripts".to_string(),
+      filename: name.to_string(),
+      arguments: vec![],
+      kill: true,
+    }
+  }
+
+  /// A fresh Flashpoint folder for `test`, and the scripts folder inside it.
+  fn fp_folder(test: &str) -> (PathBuf, PathBuf) {
+    let fp_path = std::env::temp_dir().join(format!("fp_{}_{}", test, std::process::id()));
+    let dir = fp_path.join("scripts");
+    let _ = std::fs::remove_dir_all(&fp_path);
+    std::fs::create_dir_all(&dir).unwrap();
+    (fp_path, dir)
+  }
+
+  async fn wait_for(what: &str, check: impl Fn() -> bool) {
+    for _ in 0..200 {
+      if check() {
+        return;
+      }
+      tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("timed out waiting for {}", what);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn supervises_services() {
+    let (fp_path, dir) = fp_folder("supervisor");
+    let mut lingering = script(&dir, "lingering.sh", "exec sleep 30");
+    lingering.kill = false;
+    let services = Services {
+      server: vec![script(
+        &dir,
+        "server.sh",
+        "echo hello\necho oops >&2\nexec sleep 30",
+      )],
+      daemon: vec![
+        script(&dir, "crashy.sh", "echo run >> runs.txt\nexit 3"),
+        lingering,
+      ],
+      start: vec![script(&dir, "start.sh", "touch started")],
+      stop: vec![script(&dir, "stop.sh", "touch stopped")],
+      watch: vec![],
+    };
+
+    let (tx, rx) = unbounded();
+    let logger = Logger::default();
+    let supervisor = ServiceSupervisor::new(
+      fp_path.clone(),
+      EventSender::new(tx),
+      logger.clone(),
+      SupervisorOptions {
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(40),
+        stable_after: Duration::from_secs(30),
+      },
+    );
+    supervisor.start(&services, services.server.first());
+
+    wait_for("start entry", || dir.join("started").exists()).await;
+    wait_for("crash restarts", || {
+      std::fs::read_to_string(dir.join("runs.txt")).map_or(0, |r| r.lines().count()) >= 3
+    })
+    .await;
+    wait_for("server output", || {
+      let logs = logger.entries();
+      logs
+        .iter()
+        .any(|e| e.source == "server" && e.content == "hello")
+        && logs
+          .iter()
+          .any(|e| e.content == "oops" && e.log_level == LogLevel::Error)
+    })
+    .await;
+
+    supervisor.stop(&services).await;
+    assert!(dir.join("stopped").exists());
+    let statuses = supervisor.statuses();
+    let state = |name: &str| statuses.iter().find(|s| s.name == name).unwrap().state;
+    assert_eq!(state("server"), ServiceState::Killed);
+    assert_eq!(state("lingering"), ServiceState::Detached);
+    assert_eq!(state("start"), ServiceState::Exited);
+    assert_eq!(state("stop"), ServiceState::Exited);
+    let crashy = statuses.iter().find(|s| s.name == "crashy").unwrap();
+    assert!(crashy.restarts >= 2);
+
+    drop(supervisor);
+    let events: Vec<String> = rx.collect().await;
+    assert!(events
+      .iter()
+      .any(|e| e.contains("\"service_change\"") && e.contains("\"killed\"")));
+
+    // The detached process outlives the supervisor, clean it up.
+    if let Some(pid) = statuses.iter().find(|s| s.name == "lingering").unwrap().pid {
+      let _ = std::process::Command::new("kill")
+        .arg(pid.to_string())
+        .status();
+    }
+    let _ = std::fs::remove_dir_all(&fp_path);
+  }
+
+  #[test]
+  fn detached_processes_outlive_the_backend() {
+    let (fp_path, dir) = fp_folder("detached");
+    let mut daemon = script(
+      &dir,
+      "ticker.sh",
+      "while true; do echo tick; date +%s%N > beat; sleep 0.05; done",
+    );
+    daemon.kill = false;
+    let services = Services {
+      server: vec![],
+      daemon: vec![daemon],
+      start: vec![],
+      stop: vec![],
+      watch: vec![],
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let statuses = runtime.block_on(async {
+      let supervisor = ServiceSupervisor::new(
+        fp_path.clone(),
+        EventSender::default(),
+        Logger::default(),
+        SupervisorOptions::default(),
+      );
+      supervisor.start(&services, None);
+      wait_for("first beat", || dir.join("beat").exists()).await;
+      supervisor.stop(&services).await;
+      supervisor.statuses()
+    });
+    // Shutting the runtime down is as close as we get to the backend exiting.
+    drop(runtime);
+    let pid = statuses[0].pid.unwrap();
+    assert_eq!(statuses[0].state, ServiceState::Detached);
+
+    let beat = || std::fs::read_to_string(dir.join("beat")).unwrap_or_default();
+    let before = beat();
+    std::thread::sleep(Duration::from_millis(300));
+    let still_running = beat() != before;
+    let _ = std::process::Command::new("kill")
+      .arg(pid.to_string())
+      .status();
+    let log = std::fs::read_to_string(fp_path.join(DETACHED_LOG_FOLDER).join("ticker.log"));
+    let _ = std::fs::remove_dir_all(&fp_path);
+
+    assert!(still_running);
+    assert!(log.unwrap().starts_with("tick\n"));
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn switches_servers() {
+    let (fp_path, dir) = fp_folder("switch_server");
+    let mut first = script(&dir, "first.sh", "touch first\nexec sleep 30");
+    // Replaced servers are killed even if they'd be left running on exit.
+    first.kill = false;
+    let services = Services {
+      server: vec![
+        first,
+        script(&dir, "second.sh", "touch second\nexec sleep 30"),
+      ],
+      daemon: vec![],
+      start: vec![],
+      stop: vec![],
+      watch: vec![],
+    };
+    assert_eq!(find_server(&services, "first").unwrap().name, "first");
+    let err = find_server(&services, "Apache Webserver").unwrap_err();
+    assert_eq!(err.available, vec!["first", "second"]);
+
+    let supervisor = ServiceSupervisor::new(
+      fp_path.clone(),
+      EventSender::default(),
+      Logger::default(),
+      SupervisorOptions::default(),
+    );
+    supervisor.start(&services, Some(&services.server[0]));
+    wait_for("first server", || dir.join("first").exists()).await;
+    supervisor.set_server(&services.server[1]);
+    assert_eq!(supervisor.server_name().as_deref(), Some("second"));
+    wait_for("second server", || dir.join("second").exists()).await;
+
+    let state = |name: &str| {
+      supervisor
+        .statuses()
+        .into_iter()
+        .find(|s| s.name == name)
+        .map(|s| s.state)
+    };
+    assert_eq!(state("first"), Some(ServiceState::Killed));
+    wait_for("second server status", || {
+      state("second") == Some(ServiceState::Running)
+    })
+    .await;
+    supervisor.stop(&services).await;
+    assert_eq!(state("second"), Some(ServiceState::Killed));
+    let _ = std::fs::remove_dir_all(&fp_path);
+  }
+}
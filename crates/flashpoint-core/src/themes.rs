@@ -0,0 +1,65 @@
+//! Themes in the theme folder. A theme is a folder holding a `theme.css`, which clients load; the
+//! backend only keeps track of which themes exist.
+
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// The stylesheet every theme folder must have.
+const THEME_ENTRY: &str = "theme.css";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Theme {
+  /// The theme's folder name.
+  pub id: String,
+  pub path: String,
+  /// The theme's stylesheet.
+  pub entry_path: String,
+}
+
+/// Finds every theme in `folder`, sorted by ID. Folders without a stylesheet are skipped.
+pub fn load_themes(folder: &Path) -> Vec<Theme> {
+  let entries = match fs::read_dir(folder) {
+    Ok(entries) => entries,
+    // No theme folder means no themes.
+    Err(_) => return vec![],
+  };
+  let mut themes: Vec<Theme> = entries
+    .flatten()
+    .map(|e| e.path())
+    .filter(|p| p.join(THEME_ENTRY).is_file())
+    .map(|p| Theme {
+      id: p
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default(),
+      path: p.to_string_lossy().to_string(),
+      entry_path: p.join(THEME_ENTRY).to_string_lossy().to_string(),
+    })
+    .collect();
+  themes.sort_by(|a, b| a.id.cmp(&b.id));
+  themes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finds_folders_with_a_stylesheet() {
+    let dir = std::env::temp_dir().join(format!("fp_themes_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("dark")).unwrap();
+    fs::create_dir_all(dir.join("empty")).unwrap();
+    fs::create_dir_all(dir.join("Blue")).unwrap();
+    fs::write(dir.join("dark").join(THEME_ENTRY), "body {}").unwrap();
+    fs::write(dir.join("Blue").join(THEME_ENTRY), "body {}").unwrap();
+    fs::write(dir.join("stray.css"), "body {}").unwrap();
+
+    let ids: Vec<_> = load_themes(&dir).into_iter().map(|t| t.id).collect();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(ids, vec!["Blue", "dark"]);
+    assert!(load_themes(&dir).is_empty());
+  }
+}
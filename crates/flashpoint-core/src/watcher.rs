@@ -0,0 +1,353 @@
+//! Watches folders for changes. Folders are polled rather than watched through OS notifications,
+//! which keeps this dependency free and working the same on every platform. Changes are
+//! debounced: a folder is only reported once it has been quiet for a while, so a save that
+//! touches many files comes out as a single change.
+
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchedFolder {
+  /// What the folder holds, e.g. `playlists`. Sent along with its changes.
+  pub kind: String,
+  /// A folder or a single file.
+  pub path: PathBuf,
+  /// How many levels of folders to look into. 1 only looks at the files directly in `path`.
+  pub depth: usize,
+  /// How often to rescan the folder, if it should be less often than the watcher polls.
+  pub poll_interval: Option<Duration>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderChange {
+  pub kind: String,
+  pub path: String,
+  /// Every file added, modified or removed since the last change was reported.
+  pub changed: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+  pub poll_interval: Duration,
+  /// How long a folder must go without changes before they are reported.
+  pub debounce: Duration,
+}
+
+impl Default for WatchOptions {
+  fn default() -> Self {
+    Self {
+      poll_interval: Duration::from_millis(500),
+      debounce: Duration::from_secs(1),
+    }
+  }
+}
+
+/// The modification time and size of every file in a folder.
+type Snapshot = HashMap<PathBuf, (Option<SystemTime>, u64)>;
+
+struct PendingChange {
+  changed: BTreeSet<PathBuf>,
+  last_seen: Instant,
+}
+
+struct Watched {
+  folder: WatchedFolder,
+  snapshot: Snapshot,
+  /// When the folder was last scanned. `None` until the first scan of a folder added by
+  /// `set_folders`.
+  last_scan: Option<Instant>,
+  pending: Option<PendingChange>,
+}
+
+pub struct FolderWatcher {
+  watched: Vec<Watched>,
+  opts: WatchOptions,
+}
+
+impl FolderWatcher {
+  /// Folders that don't exist yet are watched for being created.
+  pub fn new(folders: Vec<WatchedFolder>, opts: WatchOptions) -> Self {
+    let now = Instant::now();
+    let watched = folders
+      .into_iter()
+      .map(|folder| Watched {
+        snapshot: snapshot(&folder.path, folder.depth),
+        folder,
+        last_scan: Some(now),
+        pending: None,
+      })
+      .collect();
+    Self { watched, opts }
+  }
+
+  /// Swaps in a new list of folders. Folders that were already watched keep their state. New ones
+  /// are reported as changed once they settle, so whatever lives in them gets loaded.
+  pub fn set_folders(&mut self, folders: Vec<WatchedFolder>, now: Instant) {
+    if self.watched.iter().map(|w| &w.folder).eq(folders.iter()) {
+      return;
+    }
+    let mut old = std::mem::take(&mut self.watched);
+    self.watched = folders
+      .into_iter()
+      .map(|folder| match old.iter().position(|w| w.folder == folder) {
+        Some(i) => old.swap_remove(i),
+        None => Watched {
+          folder,
+          snapshot: Snapshot::new(),
+          last_scan: None,
+          pending: Some(PendingChange {
+            changed: BTreeSet::new(),
+            last_seen: now,
+          }),
+        },
+      })
+      .collect();
+  }
+
+  /// Rescans the folders that are due, returning the changes that have settled by `now`.
+  pub fn poll(&mut self, now: Instant) -> Vec<FolderChange> {
+    let mut settled = vec![];
+    for watched in &mut self.watched {
+      let due = match (watched.last_scan, watched.folder.poll_interval) {
+        (Some(last_scan), Some(interval)) => now.saturating_duration_since(last_scan) >= interval,
+        _ => true,
+      };
+      if due {
+        let current = snapshot(&watched.folder.path, watched.folder.depth);
+        let changed = diff(&watched.snapshot, &current);
+        if !changed.is_empty() {
+          let pending = watched.pending.get_or_insert_with(|| PendingChange {
+            changed: BTreeSet::new(),
+            last_seen: now,
+          });
+          pending.changed.extend(changed);
+          pending.last_seen = now;
+        }
+        watched.snapshot = current;
+        watched.last_scan = Some(now);
+      }
+
+      let quiet =
+        |p: &PendingChange| now.saturating_duration_since(p.last_seen) >= self.opts.debounce;
+      if let Some(pending) = watched.pending.take_if(|p| quiet(p)) {
+        settled.push(FolderChange {
+          kind: watched.folder.kind.clone(),
+          path: watched.folder.path.to_string_lossy().to_string(),
+          changed: pending
+            .changed
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+        });
+      }
+    }
+    settled
+  }
+
+  /// Polls in the background, calling `on_change` with every settled change. `folders` is asked
+  /// for the folders to watch before every poll, so they can change while the watcher runs. Both
+  /// run on the blocking thread pool, so they're free to do blocking IO. Must be called from
+  /// within a tokio runtime.
+  pub fn spawn<W, F>(mut self, mut folders: W, mut on_change: F) -> JoinHandle<()>
+  where
+    W: FnMut() -> Vec<WatchedFolder> + Send + 'static,
+    F: FnMut(FolderChange) + Send + 'static,
+  {
+    tokio::spawn(async move {
+      loop {
+        tokio::time::sleep(self.opts.poll_interval).await;
+        // Scanning and reloading are blocking IO, so they're done off the runtime's worker threads.
+        let res = tokio::task::spawn_blocking(move || {
+          let now = Instant::now();
+          self.set_folders(folders(), now);
+          for change in self.poll(now) {
+            on_change(change);
+          }
+          (self, folders, on_change)
+        })
+        .await;
+        match res {
+          Ok((watcher, folders_fn, callback)) => {
+            self = watcher;
+            folders = folders_fn;
+            on_change = callback;
+          }
+          Err(e) => {
+            println!("Folder watcher stopped: {}", e);
+            return;
+          }
+        }
+      }
+    })
+  }
+}
+
+/// Snapshots the files in `path`, looking `depth` levels of folders deep.
+fn snapshot(path: &Path, depth: usize) -> Snapshot {
+  let mut files = Snapshot::new();
+  add_to_snapshot(path, depth, &mut files);
+  files
+}
+
+fn add_to_snapshot(path: &Path, depth: usize, files: &mut Snapshot) {
+  let meta = match fs::metadata(path) {
+    Ok(meta) => meta,
+    // Missing, or removed while we were looking.
+    Err(_) => return,
+  };
+  if meta.is_dir() {
+    if depth == 0 {
+      return;
+    }
+    if let Ok(entries) = fs::read_dir(path) {
+      for entry in entries.flatten() {
+        add_to_snapshot(&entry.path(), depth - 1, files);
+      }
+    }
+  } else {
+    files.insert(path.to_path_buf(), (meta.modified().ok(), meta.len()));
+  }
+}
+
+fn diff(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+  let changed = new
+    .iter()
+    .filter(|(path, meta)| old.get(*path) != Some(meta))
+    .map(|(path, _)| path.clone());
+  let removed = old.keys().filter(|path| !new.contains_key(*path)).cloned();
+  changed.chain(removed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn changes_are_debounced() {
+    let dir = std::env::temp_dir().join(format!("fp_watcher_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let opts = WatchOptions {
+      poll_interval: Duration::from_millis(10),
+      debounce: Duration::from_secs(1),
+    };
+    let mut watcher = FolderWatcher::new(
+      vec![WatchedFolder {
+        kind: "playlists".to_string(),
+        path: dir.clone(),
+        depth: 2,
+        poll_interval: None,
+      }],
+      opts,
+    );
+    let start = Instant::now();
+    assert!(watcher.poll(start).is_empty());
+
+    // The folder doesn't exist yet, creating it counts as a change.
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    fs::write(dir.join("a.json"), "{}").unwrap();
+    assert!(watcher.poll(start).is_empty());
+    fs::write(dir.join("nested").join("b.json"), "{}").unwrap();
+    let later = start + Duration::from_millis(500);
+    assert!(watcher.poll(later).is_empty());
+
+    let changes = watcher.poll(later + Duration::from_secs(1));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, "playlists");
+    assert_eq!(
+      changes[0].changed,
+      vec![
+        dir.join("a.json").to_string_lossy().to_string(),
+        dir
+          .join("nested")
+          .join("b.json")
+          .to_string_lossy()
+          .to_string(),
+      ]
+    );
+    assert!(watcher.poll(later + Duration::from_secs(5)).is_empty());
+
+    // Size changes and removals are picked up too.
+    fs::write(dir.join("a.json"), "{ }").unwrap();
+    fs::remove_file(dir.join("nested").join("b.json")).unwrap();
+    let now = later + Duration::from_secs(10);
+    watcher.poll(now);
+    let changes = watcher.poll(now + Duration::from_secs(1));
+    assert_eq!(changes[0].changed.len(), 2);
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn slow_folders_are_scanned_shallowly_and_less_often() {
+    let dir = std::env::temp_dir().join(format!("fp_watcher_slow_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    let mut watcher = FolderWatcher::new(
+      vec![WatchedFolder {
+        kind: "services".to_string(),
+        path: dir.clone(),
+        depth: 1,
+        poll_interval: Some(Duration::from_secs(10)),
+      }],
+      WatchOptions::default(),
+    );
+    let start = Instant::now();
+
+    // Too deep to be seen.
+    fs::write(dir.join("nested").join("deep.log"), "").unwrap();
+    assert!(watcher.poll(start + Duration::from_secs(10)).is_empty());
+    assert!(watcher.poll(start + Duration::from_secs(20)).is_empty());
+
+    // Not rescanned until the folder's interval has passed.
+    fs::write(dir.join("top.log"), "").unwrap();
+    assert!(watcher.poll(start + Duration::from_secs(25)).is_empty());
+    watcher.poll(start + Duration::from_secs(30));
+    let changes = watcher.poll(start + Duration::from_secs(40));
+    assert_eq!(
+      changes[0].changed,
+      vec![dir.join("top.log").to_string_lossy().to_string()]
+    );
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn new_folders_are_reported_once_settled() {
+    let dir = std::env::temp_dir().join(format!("fp_watcher_swap_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for name in ["old", "new"] {
+      fs::create_dir_all(dir.join(name)).unwrap();
+    }
+    fs::write(dir.join("new").join("theme.css"), "").unwrap();
+    let folder = |name: &str| WatchedFolder {
+      kind: "themes".to_string(),
+      path: dir.join(name),
+      depth: 1,
+      poll_interval: None,
+    };
+    let mut watcher = FolderWatcher::new(vec![folder("old")], WatchOptions::default());
+    let start = Instant::now();
+
+    // Unchanged folders keep their state.
+    watcher.set_folders(vec![folder("old")], start);
+    assert!(watcher.poll(start + Duration::from_secs(5)).is_empty());
+
+    watcher.set_folders(vec![folder("new")], start + Duration::from_secs(5));
+    assert!(watcher.poll(start + Duration::from_secs(5)).is_empty());
+    let changes = watcher.poll(start + Duration::from_secs(10));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, dir.join("new").to_string_lossy());
+    assert_eq!(
+      changes[0].changed,
+      vec![dir
+        .join("new")
+        .join("theme.css")
+        .to_string_lossy()
+        .to_string()]
+    );
+    let _ = fs::remove_dir_all(&dir);
+  }
+}
@@ -1,34 +1,102 @@
+use crate::data_packs::DataPackScanReport;
+use crate::events::EventSender;
+use crate::extensions::ExtensionScan;
+use crate::launcher::{LaunchPlan, Launcher};
+use crate::logs::Logger;
+use crate::playlists::{PlaylistSync, PlaylistSyncReport};
+use crate::themes::Theme;
 use crate::FlashpointService;
 use chrono::NaiveDateTime;
 use flashpoint_config::types::*;
 use flashpoint_database::{
-  models::{Game, TagAlias, TagCategory, ViewGame},
-  tag::InsertableTagCategory,
+  game::InsertableAddApp,
+  game_data::InsertableGameData,
+  misc::UniqueValue,
+  models::{
+    AdditionalApp, Game, GameData, Playlist, PlaylistGame, Source, Tag, TagAlias, TagCategory,
+    ViewGame,
+  },
+  playlist::{InsertablePlaylist, PlaylistFilter},
+  search::SearchParseError,
+  source::{RegisterSourceSummary, SourceUrl},
+  tag::{InsertableTag, InsertableTagCategory, MergeTagsSummary, SaveableTag, TagSuggestion},
+  types::{DbState, FilterOpts, GameCursor, GamePage, ParsedSearch},
 };
 use serde::{Deserialize, Serialize};
-use std::sync::MutexGuard;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub type WebsocketRegister<RecType, ResType> = Box<
   dyn Fn(
       MutexGuard<FlashpointService>,
       RecType,
     ) -> Result<WebsocketRes<ResType>, Box<dyn std::error::Error>>
-    + Send,
+    + Send
+    + Sync,
+>;
+/// A register that only uses the database. It runs without holding the service lock, so these
+/// requests don't wait on each other, and writes are queued by the database itself.
+pub type WebsocketDbRegister<RecType, ResType> = Box<
+  dyn Fn(&DbState, RecType) -> Result<WebsocketRes<ResType>, Box<dyn std::error::Error>>
+    + Send
+    + Sync,
+>;
+/// A register that needs more of the service than the database, but does slow or blocking work.
+/// It gets a copy of what it needs and runs on the blocking pool without holding the service lock.
+pub type WebsocketBlockingRegister<RecType, ResType> = Box<
+  dyn Fn(&ServiceHandle, RecType) -> Result<WebsocketRes<ResType>, Box<dyn std::error::Error>>
+    + Send
+    + Sync,
 >;
 // pub type WebsocketRegisterAlone<RecType, ResType> = Box<dyn Fn(RecType) -> ResType + Send>;
 
+/// The parts of the service a `WebsocketBlockingRegister` can use, copied out of the service.
+#[derive(Clone)]
+pub struct ServiceHandle {
+  /// For the rare register that has to change the service. Lock it only briefly.
+  pub service: Arc<Mutex<FlashpointService>>,
+  pub prefs_lock: Arc<Mutex<()>>,
+  pub db: Arc<DbState>,
+  pub fp_path: PathBuf,
+  pub prefs: Preferences,
+  pub events: EventSender,
+  pub playlist_sync: Arc<Mutex<PlaylistSync>>,
+  pub logger: Logger,
+  pub launcher: Launcher,
+}
+
+impl ServiceHandle {
+  /// Saves a change to the preferences, then applies it. The service is only locked to read the
+  /// current preferences and to swap in the new ones.
+  pub fn update_prefs(
+    &self,
+    patch: serde_json::Value,
+  ) -> Result<Preferences, Box<dyn std::error::Error>> {
+    let _saving = self.prefs_lock.lock().unwrap();
+    let current = self.service.lock().unwrap().prefs.clone();
+    let prefs = crate::save_prefs(&self.fp_path, &current, patch)?;
+    flashpoint_database::filter::set_game_filters(&self.db, &prefs);
+    self.service.lock().unwrap().prefs = prefs.clone();
+    Ok(prefs)
+  }
+
+  /// Writes a playlist back to its file after it was changed in the database.
+  pub fn sync_playlist(&self, playlist_id: &str) {
+    let mut playlist_sync = self.playlist_sync.lock().unwrap();
+    if let Err(e) = playlist_sync.export(&self.db, playlist_id) {
+      println!("Failed to save playlist {}: {}", playlist_id, e);
+    }
+  }
+}
+
 #[derive(Debug, Serialize)]
 pub struct InitDataRes {
   pub config: Config,
   pub prefs: Preferences,
   #[cfg(feature = "services")]
   pub services_info: Services,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct AddRecData {
-  pub first: i32,
-  pub second: i32,
+  pub themes: Vec<Theme>,
+  pub extensions: ExtensionScan,
 }
 
 #[derive(Debug, Serialize)]
@@ -52,14 +120,258 @@ pub struct TagRes {
   pub aliases: Vec<TagAlias>,
 }
 
+impl TagRes {
+  pub fn new(tag: Tag, aliases: Vec<TagAlias>) -> Result<Self, Box<dyn std::error::Error>> {
+    let primary = aliases
+      .iter()
+      .find(|a| Some(a.id) == tag.primary_alias_id)
+      .ok_or_else(|| format!("tag {} has no primary alias", tag.id))?
+      .clone();
+    Ok(Self {
+      id: tag.id,
+      date_modified: tag.date_modified,
+      category_id: tag.category_id,
+      description: tag.description,
+      primary_alias: primary,
+      aliases,
+    })
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveTagRecv {
+  pub id: i32,
+  #[serde(flatten)]
+  pub changes: SaveableTag,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddTagAliasRecv {
+  pub tag_id: i32,
+  pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagSuggestionsRecv {
+  pub partial: String,
+  #[serde(default = "default_suggestion_limit")]
+  pub limit: usize,
+}
+
+fn default_suggestion_limit() -> usize {
+  25
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveTagAliasRecv {
+  pub id: i32,
+  pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetPrimaryAliasRecv {
+  pub tag_id: i32,
+  pub alias_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeTagsRecv {
+  pub source_id: i32,
+  pub target_id: i32,
+  #[serde(default = "bool_true")]
+  pub move_aliases: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistGameRecv {
+  pub playlist_id: String,
+  pub game_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddPlaylistGameRecv {
+  pub playlist_id: String,
+  pub game_id: String,
+  pub notes: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePlaylistGameRecv {
+  pub id: i32,
+  pub notes: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MovePlaylistGameRecv {
+  pub playlist_id: String,
+  pub game_id: String,
+  pub index: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlaylistGamesRecv {
+  pub playlist_id: String,
+  pub game_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchGamesRecv {
+  #[serde(default)]
+  pub filters: FilterOpts,
+  /// Search text in the launcher's search syntax. Replaces `filters.searchQuery` when set.
+  pub query: Option<String>,
+  /// A game column or field name, e.g. "title" or "dateAdded".
+  pub order_by: Option<String>,
+  #[serde(default = "bool_true")]
+  pub ascending: bool,
+  pub cursor: Option<GameCursor>,
+}
+
+#[derive(Deserialize)]
+pub struct RandomGamesRecv {
+  pub count: usize,
+  pub library: Option<String>,
+  /// Search text in the launcher's search syntax, limiting which games can be picked.
+  pub query: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UniqueValuesRecv {
+  /// A game column or field name, e.g. "developer" or "playMode".
+  pub column: String,
+  pub library: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FindPlatformsRecv {
+  pub library: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetActiveGameDataRecv {
+  pub game_id: String,
+  /// None clears the game's active data.
+  pub game_data_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct SetGameDataPresentRecv {
+  pub id: i32,
+  /// Where the data pack is on disk, or None if it's absent.
+  pub path: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DataPackScanRecv {
+  /// Check the sha256 and crc32 of every matched file. Slow on large folders.
+  pub verify: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterSourceRecv {
+  pub name: String,
+  pub base_url: String,
+  /// Path to the source's index file.
+  pub index_path: String,
+}
+
+/// Either the parsed search, or every error found in it.
+#[derive(Debug, Serialize)]
+pub struct ParseSearchRes {
+  pub search: Option<ParsedSearch>,
+  pub errors: Vec<SearchParseError>,
+}
+
+/// Either a page of games, or every error found in the search text. The page's fields are
+/// flattened into the response, next to `errors`.
+#[derive(Debug, Serialize)]
+pub struct SearchGamesRes {
+  #[serde(flatten)]
+  pub page: Option<GamePage>,
+  pub errors: Vec<SearchParseError>,
+}
+
+/// Either the picked games, or every error found in the search text.
+#[derive(Debug, Serialize)]
+pub struct RandomGamesRes {
+  pub games: Vec<Game>,
+  pub errors: Vec<SearchParseError>,
+}
+
+fn bool_true() -> bool {
+  true
+}
+
 pub struct WebsocketRegisters {
   pub init_data: WebsocketRegister<(), InitDataRes>,
-  pub view_all_games: WebsocketRegister<(), Vec<ViewGame>>,
-  pub all_games: WebsocketRegister<(), Vec<Game>>,
-  pub all_tag_categories: WebsocketRegister<(), Vec<TagCategory>>,
-  pub create_tag_category: WebsocketRegister<InsertableTagCategory, TagCategory>,
-  pub find_tag_by_name: WebsocketRegister<String, TagRes>,
-  pub find_games_with_tag: WebsocketRegister<String, Vec<Game>>,
+  /// Takes the preference fields to change, and returns the updated preferences.
+  pub update_prefs: WebsocketBlockingRegister<serde_json::Value, Preferences>,
+  pub view_all_games: WebsocketDbRegister<(), Vec<ViewGame>>,
+  pub all_games: WebsocketDbRegister<(), Vec<Game>>,
+  pub all_tag_categories: WebsocketDbRegister<(), Vec<TagCategory>>,
+  pub create_tag_category: WebsocketDbRegister<InsertableTagCategory, TagCategory>,
+  pub find_tag_by_name: WebsocketDbRegister<String, TagRes>,
+  pub find_tags: WebsocketDbRegister<(), Vec<TagRes>>,
+  pub get_tag: WebsocketDbRegister<i32, TagRes>,
+  pub create_tag: WebsocketDbRegister<InsertableTag, TagRes>,
+  pub delete_tag: WebsocketDbRegister<i32, usize>,
+  pub save_tag: WebsocketDbRegister<SaveTagRecv, Tag>,
+  pub add_alias_to_tag: WebsocketDbRegister<AddTagAliasRecv, TagAlias>,
+  pub save_tag_alias: WebsocketDbRegister<SaveTagAliasRecv, TagAlias>,
+  pub remove_tag_alias: WebsocketDbRegister<i32, usize>,
+  pub set_primary_alias: WebsocketDbRegister<SetPrimaryAliasRecv, TagRes>,
+  pub merge_tags: WebsocketDbRegister<MergeTagsRecv, MergeTagsSummary>,
+  pub find_tag_suggestions: WebsocketDbRegister<TagSuggestionsRecv, Vec<TagSuggestion>>,
+  pub find_playlists: WebsocketDbRegister<PlaylistFilter, Vec<Playlist>>,
+  pub find_playlist: WebsocketDbRegister<String, Playlist>,
+  pub create_playlist: WebsocketBlockingRegister<InsertablePlaylist, Playlist>,
+  pub update_playlist: WebsocketBlockingRegister<Playlist, Playlist>,
+  pub remove_playlist: WebsocketBlockingRegister<String, usize>,
+  pub find_playlist_games: WebsocketDbRegister<String, Vec<PlaylistGame>>,
+  pub add_playlist_game: WebsocketBlockingRegister<AddPlaylistGameRecv, PlaylistGame>,
+  pub remove_playlist_game: WebsocketBlockingRegister<PlaylistGameRecv, usize>,
+  pub update_playlist_game: WebsocketBlockingRegister<UpdatePlaylistGameRecv, PlaylistGame>,
+  pub move_playlist_game: WebsocketBlockingRegister<MovePlaylistGameRecv, Vec<PlaylistGame>>,
+  pub update_playlist_games: WebsocketBlockingRegister<UpdatePlaylistGamesRecv, Vec<PlaylistGame>>,
+  pub playlist_sync_report: WebsocketRegister<(), PlaylistSyncReport>,
+  pub find_games_with_tag: WebsocketDbRegister<String, Vec<Game>>,
+  pub find_add_app: WebsocketDbRegister<String, AdditionalApp>,
+  pub find_add_apps: WebsocketDbRegister<String, Vec<AdditionalApp>>,
+  pub create_add_app: WebsocketDbRegister<InsertableAddApp, AdditionalApp>,
+  pub save_add_app: WebsocketDbRegister<AdditionalApp, AdditionalApp>,
+  pub remove_add_app: WebsocketDbRegister<String, usize>,
+  pub find_game_data: WebsocketDbRegister<String, Vec<GameData>>,
+  pub import_game_data: WebsocketDbRegister<InsertableGameData, GameData>,
+  pub set_active_game_data: WebsocketDbRegister<SetActiveGameDataRecv, Game>,
+  pub set_game_data_present: WebsocketDbRegister<SetGameDataPresentRecv, GameData>,
+  pub scan_data_packs: WebsocketBlockingRegister<DataPackScanRecv, DataPackScanReport>,
+  pub find_sources: WebsocketDbRegister<(), Vec<Source>>,
+  pub register_source: WebsocketBlockingRegister<RegisterSourceRecv, RegisterSourceSummary>,
+  pub remove_source: WebsocketDbRegister<i32, usize>,
+  pub find_source_urls: WebsocketDbRegister<String, Vec<SourceUrl>>,
+  pub search_games: WebsocketDbRegister<SearchGamesRecv, SearchGamesRes>,
+  pub find_random_games: WebsocketDbRegister<RandomGamesRecv, RandomGamesRes>,
+  pub find_unique_values: WebsocketDbRegister<UniqueValuesRecv, Vec<String>>,
+  pub find_unique_values_in_order: WebsocketDbRegister<UniqueValuesRecv, Vec<UniqueValue>>,
+  pub find_platforms: WebsocketDbRegister<FindPlatformsRecv, Vec<String>>,
+  /// Stops the running server and starts the one with the given name instead.
+  #[cfg(feature = "services")]
+  pub switch_server: WebsocketRegister<String, BackProcessInfo>,
+  /// Starts a game, by ID, and the additional apps that run before it.
+  pub launch_game: WebsocketBlockingRegister<String, LaunchPlan>,
+  pub parse_search: WebsocketRegister<String, ParseSearchRes>,
   pub add: WebsocketRegister<AddRecv, i32>,
 }
 
@@ -118,6 +430,90 @@ macro_rules! ws_execute {
   };
 }
 
+#[macro_export]
+macro_rules! ws_execute_db {
+  // String rec type
+  ($func_data:expr, $register:expr, $res_str:expr, $fp_service:expr, $cancel:expr, String) => {
+    ws_execute_db!(
+      $func_data.as_str().unwrap().to_string(),
+      $register,
+      $res_str,
+      $fp_service,
+      $cancel
+    );
+  };
+  // JSON rec type
+  ($func_data:expr, $register:expr, $res_str:expr, $fp_service:expr, $cancel:expr, $rectype:ident) => {
+    let data_str = serde_json::to_string($func_data)?;
+    let data: $rectype = serde_json::from_str(data_str.as_str())?;
+    ws_execute_db!(data, $register, $res_str, $fp_service, $cancel);
+  };
+  // Data already deserialized
+  ($func_data:expr, $register:expr, $res_str:expr, $fp_service:expr, $cancel:expr) => {
+    // Only hold the service for long enough to get a handle on the database.
+    let db = {
+      let mut fp_service = $fp_service.lock().unwrap();
+      if !fp_service.initialized {
+        fp_service.init();
+      }
+      AsyncDb::new(fp_service.db.clone())
+    };
+    let data = $func_data;
+    // Errors are turned into strings on the blocking thread, they can't be sent back as they are.
+    $res_str = db
+      .run($cancel, move |db| -> Result<String, String> {
+        let res = ($register)(db, data).map_err(|e| e.to_string())?;
+        serde_json::to_string(&res).map_err(|e| e.to_string())
+      })
+      .await??;
+  };
+  // No Data
+  ($register:expr, $res_str:expr, $fp_service:expr, $cancel:expr) => {
+    ws_execute_db!((), $register, $res_str, $fp_service, $cancel);
+  };
+}
+
+#[macro_export]
+macro_rules! ws_execute_blocking {
+  // String rec type
+  ($func_data:expr, $register:expr, $res_str:expr, $fp_service:expr, $cancel:expr, String) => {
+    ws_execute_blocking!(
+      $func_data.as_str().unwrap().to_string(),
+      $register,
+      $res_str,
+      $fp_service,
+      $cancel
+    );
+  };
+  // JSON rec type
+  ($func_data:expr, $register:expr, $res_str:expr, $fp_service:expr, $cancel:expr, $rectype:ident) => {
+    let data_str = serde_json::to_string($func_data)?;
+    let data: $rectype = serde_json::from_str(data_str.as_str())?;
+    ws_execute_blocking!(data, $register, $res_str, $fp_service, $cancel);
+  };
+  // Data already deserialized
+  ($func_data:expr, $register:expr, $res_str:expr, $fp_service:expr, $cancel:expr) => {
+    // Only hold the service for long enough to copy out what the register needs.
+    let (db, handle) = {
+      let mut fp_service = $fp_service.lock().unwrap();
+      if !fp_service.initialized {
+        fp_service.init();
+      }
+      (
+        AsyncDb::new(fp_service.db.clone()),
+        fp_service.handle(&$fp_service),
+      )
+    };
+    let data = $func_data;
+    $res_str = db
+      .run($cancel, move |_| -> Result<String, String> {
+        let res = ($register)(&handle, data).map_err(|e| e.to_string())?;
+        serde_json::to_string(&res).map_err(|e| e.to_string())
+      })
+      .await??;
+  };
+}
+
 // #[macro_export]
 // macro_rules! ws_execute_alone {
 //   // String rec type
@@ -0,0 +1,211 @@
+//! Games hidden by the user's preferences: tag filter groups, extreme games and broken games.
+//! Once set with `set_game_filters`, these apply to every game query the crate makes.
+
+use crate::schema::{game, game_tags_tag, tag, tag_alias, tag_category};
+use crate::types::{DbState, GameExclusions};
+use diesel::helper_types::IntoBoxed;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
+use flashpoint_config::types::{Preferences, TagFilterGroup};
+use std::collections::HashSet;
+
+/// Resolves the preferences into the exclusions applied to every following game query.
+pub fn set_game_filters(state: &DbState, prefs: &Preferences) {
+  let show_extreme = prefs.browse_page_show_extreme && !prefs.disable_extreme_games;
+  let (tags, categories) = resolve_tag_filter_groups(&prefs.tag_filters, show_extreme);
+  *state.exclusions.write().unwrap_or_else(|e| e.into_inner()) = GameExclusions {
+    tags,
+    categories,
+    hide_extreme: !show_extreme,
+    hide_broken: !prefs.show_broken_games,
+    tag_filters_in_curate: prefs.tag_filters_in_curate,
+    excluded_random_libraries: prefs.excluded_random_libraries.clone(),
+  };
+}
+
+/// Collects the tag names and category names of every active group, including their child groups.
+/// A group is active if it's enabled, or if it's an extreme group and extreme games are hidden.
+pub fn resolve_tag_filter_groups(
+  groups: &[TagFilterGroup],
+  show_extreme: bool,
+) -> (Vec<String>, Vec<String>) {
+  let mut visited = HashSet::new();
+  let mut tags = vec![];
+  let mut categories = vec![];
+  for group in groups {
+    if group.enabled || (group.extreme && !show_extreme) {
+      collect_group(groups, group, &mut visited, &mut tags, &mut categories);
+    }
+  }
+  (tags, categories)
+}
+
+fn collect_group<'a>(
+  groups: &'a [TagFilterGroup],
+  group: &'a TagFilterGroup,
+  visited: &mut HashSet<&'a str>,
+  tags: &mut Vec<String>,
+  categories: &mut Vec<String>,
+) {
+  // Also guards against groups that (indirectly) list themselves as children.
+  if !visited.insert(group.name.as_str()) {
+    return;
+  }
+  for t in &group.tags {
+    if !tags.contains(t) {
+      tags.push(t.clone());
+    }
+  }
+  for c in &group.categories {
+    if !categories.contains(c) {
+      categories.push(c.clone());
+    }
+  }
+  for child_name in &group.child_filters {
+    if let Some(child) = groups.iter().find(|g| &g.name == child_name) {
+      collect_group(groups, child, visited, tags, categories);
+    }
+  }
+}
+
+/// Builds a subquery selecting the IDs of every tag hidden by the exclusions, either directly by
+/// one of its aliases or through its category.
+pub(crate) fn get_excluded_tag_ids_query<'a>(
+  exclusions: &GameExclusions,
+) -> tag::BoxedQuery<'a, Sqlite, diesel::sql_types::Integer> {
+  tag::table
+    .select(tag::id)
+    .filter(
+      tag::id
+        .nullable()
+        .eq_any(
+          tag_alias::table
+            .filter(tag_alias::name.eq_any(exclusions.tags.clone()))
+            .select(tag_alias::tagId),
+        )
+        .or(
+          tag::categoryId.eq_any(
+            tag_category::table
+              .filter(tag_category::name.eq_any(exclusions.categories.clone()))
+              .select(tag_category::id.nullable()),
+          ),
+        ),
+    )
+    .into_boxed()
+}
+
+/// Hides every game excluded by the preferences. Tag filter groups are skipped for curation
+/// queries unless `tag_filters_in_curate` is set.
+pub(crate) fn apply_game_exclusions<'a>(
+  mut q: IntoBoxed<'a, game::table, Sqlite>,
+  exclusions: &GameExclusions,
+  curate: bool,
+) -> IntoBoxed<'a, game::table, Sqlite> {
+  if exclusions.hide_extreme {
+    q = q.filter(game::extreme.eq(false));
+  }
+  if exclusions.hide_broken {
+    q = q.filter(game::broken.eq(false));
+  }
+  let has_tag_filters = !exclusions.tags.is_empty() || !exclusions.categories.is_empty();
+  if has_tag_filters && (!curate || exclusions.tag_filters_in_curate) {
+    q = q.filter(game::id.ne_all(get_excluded_game_ids_query(exclusions)));
+  }
+  q
+}
+
+fn get_excluded_game_ids_query<'a>(
+  exclusions: &GameExclusions,
+) -> game_tags_tag::BoxedQuery<'a, Sqlite, Text> {
+  game_tags_tag::table
+    .select(game_tags_tag::gameId)
+    .filter(game_tags_tag::tagId.eq_any(get_excluded_tag_ids_query(exclusions)))
+    .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn group(name: &str, enabled: bool, tags: &[&str], children: &[&str]) -> TagFilterGroup {
+    TagFilterGroup {
+      name: name.to_string(),
+      description: String::new(),
+      enabled,
+      tags: tags.iter().map(|t| t.to_string()).collect(),
+      categories: vec![],
+      child_filters: children.iter().map(|c| c.to_string()).collect(),
+      extreme: false,
+    }
+  }
+
+  #[test]
+  fn resolve_groups_with_cycles() {
+    let mut groups = vec![
+      group("A", true, &["a"], &["B"]),
+      group("B", false, &["b"], &["C"]),
+      group("C", false, &["c"], &["A", "Missing"]),
+      group("D", false, &["d"], &[]),
+      group("E", false, &["e"], &[]),
+    ];
+    groups[4].extreme = true;
+
+    let (tags, _) = resolve_tag_filter_groups(&groups, true);
+    assert_eq!(tags, vec!["a", "b", "c"]);
+    let (tags, _) = resolve_tag_filter_groups(&groups, false);
+    assert_eq!(tags, vec!["a", "b", "c", "e"]);
+  }
+
+  #[test]
+  fn exclusions_hide_games() {
+    use crate::game::{save_game, search_games};
+    use crate::tag::{create_tag, InsertableTag};
+    use crate::types::FilterOpts;
+    use crate::{test_game, test_state};
+
+    let state = test_state();
+    let (gore, _) = create_tag(
+      &state,
+      InsertableTag {
+        name: "Gore".to_string(),
+        category_id: None,
+        description: None,
+      },
+    )
+    .unwrap();
+    let mut extreme = test_game("b", "b");
+    extreme.extreme = true;
+    let mut broken = test_game("c", "c");
+    broken.broken = true;
+    for g in [test_game("a", "a"), extreme, broken, test_game("d", "d")] {
+      save_game(&state, g).unwrap();
+    }
+    diesel::insert_into(game_tags_tag::table)
+      .values((
+        game_tags_tag::gameId.eq("d"),
+        game_tags_tag::tagId.eq(gore.id),
+      ))
+      .execute(&mut *state.write().unwrap())
+      .unwrap();
+
+    let search = |state: &DbState, curate: bool| {
+      let filters = FilterOpts {
+        curate,
+        ..Default::default()
+      };
+      let page = search_games(state, filters, None, None).unwrap();
+      page.games.into_iter().map(|g| g.id).collect::<Vec<_>>()
+    };
+    assert_eq!(search(&state, false), vec!["a", "b", "c", "d"]);
+
+    let prefs: Preferences = serde_json::from_value(serde_json::json!({
+      "tagFilters": [group("No gore", true, &["Gore"], &[])],
+    }))
+    .unwrap();
+    set_game_filters(&state, &prefs);
+    assert_eq!(search(&state, false), vec!["a"]);
+    // Tag filter groups don't apply to curation unless asked to.
+    assert_eq!(search(&state, true), vec!["a", "d"]);
+  }
+}
@@ -1,15 +1,44 @@
+use diesel::dsl::sql;
 use diesel::helper_types::IntoBoxed;
 use diesel::prelude::*;
+use diesel::sql_types::{Integer, Text};
 use diesel::sqlite::Sqlite;
+use serde::Deserialize;
 
+use crate::filter::apply_game_exclusions;
 use crate::models::GameRelation;
-use crate::models::{Game, TagAlias, ViewGame};
+use crate::models::{AdditionalApp, Game, TagAlias, ViewGame};
+use crate::schema::additional_app;
 use crate::schema::game;
 use crate::schema::game_tags_tag;
+use crate::schema::playlist_game;
 use crate::schema::tag_alias;
-use crate::types::{DbState, FilterOpts};
+use crate::tag::get_filter_ids_query;
+use crate::types::{
+  DbErrors, DbState, FilterOpts, GameCursor, GameExclusions, GamePage, OrNotFound, RandomGameOpts,
+};
 
-pub fn view_all_games(state: &mut DbState) -> Vec<ViewGame> {
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertableAddApp {
+  pub parent_game_id: String,
+  pub name: String,
+  pub application_path: String,
+  #[serde(default)]
+  pub launch_command: String,
+  #[serde(default)]
+  pub auto_run_before: bool,
+  #[serde(default)]
+  pub wait_for_exit: bool,
+}
+
+/// The page size used by `search_games` when `FilterOpts::search_limit` isn't set.
+pub const DEFAULT_PAGE_SIZE: i64 = 1000;
+/// The most games `find_random_games` returns in one call.
+pub const MAX_RANDOM_GAMES: usize = 1000;
+
+pub fn view_all_games(state: &DbState) -> Result<Vec<ViewGame>, DbErrors> {
+  let conn = &mut state.read()?;
   game::table
     .select((
       game::id,
@@ -20,57 +49,285 @@ pub fn view_all_games(state: &mut DbState) -> Vec<ViewGame> {
       game::platform,
       game::tagsStr,
     ))
-    .load::<ViewGame>(&mut state.conn)
-    .expect("Error loading posts")
+    .load::<ViewGame>(conn)
+    .map_err(DbErrors::from)
 }
 
-pub fn find_all_games(state: &mut DbState) -> Vec<Game> {
-  game::table
-    .load::<Game>(&mut state.conn)
-    .expect("Error loading posts")
+pub fn find_all_games(state: &DbState) -> Result<Vec<Game>, DbErrors> {
+  let conn = &mut state.read()?;
+  Ok(game::table.load::<Game>(conn)?)
 }
 
-pub fn find_game(state: &mut DbState, game_id: String) -> Result<Game, diesel::result::Error> {
-  game::table.find(game_id).first(&mut state.conn)
+pub fn find_game(state: &DbState, game_id: String) -> Result<Game, DbErrors> {
+  let conn = &mut state.read()?;
+  game::table
+    .find(&game_id)
+    .first(conn)
+    .or_not_found("game", game_id)
 }
 
 // find_game_row (?)
 
-// find_random_games
+/// Picks up to `opts.count` distinct games at random, never from the excluded random libraries.
+/// At most `MAX_RANDOM_GAMES` are returned however many are asked for.
+pub fn find_random_games(state: &DbState, opts: RandomGameOpts) -> Result<Vec<Game>, DbErrors> {
+  let conn = &mut state.read()?;
+  let exclusions = state.exclusions().clone();
+  let filters = FilterOpts {
+    search_query: opts.search_query,
+    ..Default::default()
+  };
+  let query = || {
+    let mut q = get_game_query(&filters, &exclusions, None, None, None);
+    if let Some(library) = &opts.library {
+      q = q.filter(game::library.eq(library.clone()));
+    }
+    if !exclusions.excluded_random_libraries.is_empty() {
+      q = q.filter(game::library.ne_all(exclusions.excluded_random_libraries.clone()));
+    }
+    q
+  };
+
+  Ok(
+    query()
+      .order(sql::<Integer>("random()"))
+      .limit(opts.count.min(MAX_RANDOM_GAMES) as i64)
+      .load(conn)?,
+  )
+}
 
-// find_game_page_keyset
+/// Returns one page of games matching the filters, along with a cursor to the next page and the
+/// total number of matches. Pages are ordered by `order`, or by title when it's None, except for
+/// playlist searches, which default to playlist order. Games hidden by the preferences are skipped.
+pub fn search_games(
+  state: &DbState,
+  filters: FilterOpts,
+  order: Option<(GameRelation, bool)>,
+  cursor: Option<GameCursor>,
+) -> Result<GamePage, DbErrors> {
+  let conn = &mut state.read()?;
+  let page_size = filters.search_limit.unwrap_or(DEFAULT_PAGE_SIZE);
+  let order = match order {
+    None if filters.playlist_id.is_none() => Some((GameRelation::title(String::new()), true)),
+    o => o,
+  };
+  let exclusions = state.exclusions().clone();
 
-// find_add_app
+  let total = get_game_query(&filters, &exclusions, None, None, None)
+    .count()
+    .get_result::<i64>(conn)?;
+
+  let query = match (&order, &cursor) {
+    (Some((key, asc)), Some(c)) => {
+      let cursor_key = match &c.key {
+        Some(k) if std::mem::discriminant(k) == std::mem::discriminant(key) => k,
+        _ => {
+          return Err(DbErrors::InvalidArgument(
+            "cursor does not match the requested order".to_string(),
+          ))
+        }
+      };
+      get_game_query(
+        &filters,
+        &exclusions,
+        Some((cursor_key, *asc)),
+        Some(c.skip),
+        Some(page_size),
+      )
+    }
+    (Some((key, asc)), None) => {
+      get_game_query(&filters, &exclusions, Some((key, *asc)), None, None).limit(page_size)
+    }
+    (None, c) => get_game_query(
+      &filters,
+      &exclusions,
+      None,
+      c.as_ref().map(|c| c.skip),
+      Some(page_size),
+    ),
+  };
+  let games = query.load::<Game>(conn)?;
+
+  let next_cursor = match games.last() {
+    Some(last) if games.len() as i64 == page_size => Some(match &order {
+      Some((key, _)) => {
+        let last_key = key.with_value_from(last);
+        let same = games
+          .iter()
+          .rev()
+          .take_while(|g| key.with_value_from(g) == last_key)
+          .count() as i64;
+        // If the whole page shared the cursor's key, keep counting from where it left off.
+        let skip = match &cursor {
+          Some(c) if same == page_size && c.key.as_ref() == Some(&last_key) => c.skip + same,
+          _ => same,
+        };
+        GameCursor {
+          key: Some(last_key),
+          skip,
+        }
+      }
+      None => GameCursor {
+        key: None,
+        skip: cursor.map(|c| c.skip).unwrap_or(0) + page_size,
+      },
+    }),
+    _ => None,
+  };
+
+  Ok(GamePage {
+    games,
+    next_cursor,
+    total,
+  })
+}
+
+pub fn find_add_app(state: &DbState, add_app_id: String) -> Result<AdditionalApp, DbErrors> {
+  let conn = &mut state.read()?;
+  additional_app::table
+    .find(&add_app_id)
+    .first(conn)
+    .or_not_found("additional app", add_app_id)
+}
+
+/// Loads every additional app of a game, sorted by name.
+pub fn find_add_apps(state: &DbState, game_id: String) -> Result<Vec<AdditionalApp>, DbErrors> {
+  let conn = &mut state.read()?;
+  additional_app::table
+    .filter(additional_app::parentGameId.eq(game_id))
+    .order((additional_app::name.asc(), additional_app::id.asc()))
+    .load(conn)
+    .map_err(DbErrors::from)
+}
+
+/// Loads the additional apps to run before a game, in the order they should run.
+pub fn find_auto_run_before_add_apps(
+  state: &DbState,
+  game_id: String,
+) -> Result<Vec<AdditionalApp>, DbErrors> {
+  let conn = &mut state.read()?;
+  additional_app::table
+    .filter(additional_app::parentGameId.eq(game_id))
+    .filter(additional_app::autoRunBefore.eq(true))
+    .order((additional_app::name.asc(), additional_app::id.asc()))
+    .load(conn)
+    .map_err(DbErrors::from)
+}
+
+pub fn create_add_app(
+  state: &DbState,
+  new_add_app: InsertableAddApp,
+) -> Result<AdditionalApp, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    game::table
+      .find(&new_add_app.parent_game_id)
+      .select(game::id)
+      .first::<String>(conn)
+      .or_not_found("game", &new_add_app.parent_game_id)?;
+    let add_app = AdditionalApp {
+      id: uuid::Uuid::new_v4().to_string(),
+      application_path: new_add_app.application_path,
+      auto_run_before: new_add_app.auto_run_before,
+      launch_command: new_add_app.launch_command,
+      name: new_add_app.name,
+      wait_for_exit: new_add_app.wait_for_exit,
+      parent_game_id: Some(new_add_app.parent_game_id),
+    };
+    diesel::insert_into(additional_app::table)
+      .values(&add_app)
+      .execute(conn)?;
+    Ok(add_app)
+  })
+}
+
+/// Overwrites every field of an existing additional app.
+pub fn save_add_app(state: &DbState, add_app: AdditionalApp) -> Result<AdditionalApp, DbErrors> {
+  let conn = &mut *state.write()?;
+  let updated = diesel::update(additional_app::table.find(&add_app.id))
+    .set(&add_app)
+    .execute(conn)?;
+  if updated == 0 {
+    return Err(DbErrors::NotFound {
+      entity: "additional app",
+      key: add_app.id,
+    });
+  }
+  Ok(add_app)
+}
+
+pub fn remove_add_app(state: &DbState, add_app_id: String) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  Ok(diesel::delete(additional_app::table.find(add_app_id)).execute(conn)?)
+}
 
 // update_games
 
-pub fn save_game(state: &mut DbState, g: Game) -> Result<usize, diesel::result::Error> {
-  diesel::insert_into(game::table)
-    .values(&g)
-    .execute(&mut state.conn)
+pub fn save_game(state: &DbState, g: Game) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  Ok(diesel::insert_into(game::table).values(&g).execute(conn)?)
 }
 
-pub fn remove_game(state: &mut DbState, game_id: String) -> Result<usize, diesel::result::Error> {
-  diesel::delete(game::table.find(game_id)).execute(&mut state.conn)
+pub fn remove_game(state: &DbState, game_id: String) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  Ok(diesel::delete(game::table.find(game_id)).execute(conn)?)
 }
 
-pub fn find_games_with_tag(state: &mut DbState, tag_str: String) -> Vec<Game> {
+pub fn find_games_with_tag(state: &DbState, tag_str: String) -> Result<Vec<Game>, DbErrors> {
+  let conn = &mut state.read()?;
   let alias = tag_alias::table
-    .filter(tag_alias::name.eq(tag_str))
-    .first::<TagAlias>(&mut state.conn)
-    .expect("Tag alias not found");
+    .filter(tag_alias::name.eq(&tag_str))
+    .first::<TagAlias>(conn)
+    .or_not_found("tag alias", &tag_str)?;
+  let tag_id = alias.tag_id.ok_or(DbErrors::BrokenReference {
+    entity: "tag alias",
+    key: tag_str,
+    missing: "tag",
+  })?;
 
   let game_ids = game_tags_tag::table
-    .filter(game_tags_tag::tagId.eq(alias.tag_id.expect("Tried to unwrap broken alias")))
+    .filter(game_tags_tag::tagId.eq(tag_id))
     .select(game_tags_tag::gameId);
 
-  game::table
-    .filter(game::id.eq_any(game_ids))
-    .load::<Game>(&mut state.conn)
-    .expect("Error loading posts")
+  Ok(
+    apply_game_exclusions(game::table.into_boxed(), &state.exclusions(), false)
+      .filter(game::id.eq_any(game_ids))
+      .load::<Game>(conn)?,
+  )
   // TODO: Do we attach the other fields?
 }
 
+/// Recomputes `tagsStr` for the given games from the primary aliases of their tags.
+/// Must be called whenever a game's tags or a tag's primary alias changes.
+pub(crate) fn rebuild_tags_str(
+  conn: &mut SqliteConnection,
+  game_ids: &[String],
+) -> Result<(), DbErrors> {
+  // One statement per chunk, kept under SQLite's bound variable limit.
+  for chunk in game_ids.chunks(500) {
+    let placeholders = vec!["?"; chunk.len()].join(", ");
+    let mut query = diesel::sql_query(format!(
+      "UPDATE game SET tagsStr = COALESCE((
+         SELECT group_concat(name, '; ') FROM (
+           SELECT tag_alias.name FROM game_tags_tag
+           INNER JOIN tag ON tag.id = game_tags_tag.tagId
+           INNER JOIN tag_alias ON tag_alias.id = tag.primaryAliasId
+           WHERE game_tags_tag.gameId = game.id
+           ORDER BY tag_alias.name ASC
+         )
+       ), '')
+       WHERE id IN ({})",
+      placeholders
+    ))
+    .into_boxed();
+    for game_id in chunk {
+      query = query.bind::<Text, _>(game_id);
+    }
+    query.execute(conn)?;
+  }
+  Ok(())
+}
+
 // chunk_find_by_ids
 
 // apply_flat_game_filters
@@ -79,18 +336,52 @@ pub fn find_games_with_tag(state: &mut DbState, tag_str: String) -> Vec<Game> {
 
 // do_where_field
 
-// apply_tag_filters
+/// Restricts the query to games that have every whitelisted tag and none of the blacklisted ones.
+fn apply_tag_filters<'a>(
+  mut q: IntoBoxed<'a, game::table, Sqlite>,
+  whitelist: &[String],
+  blacklist: &[String],
+) -> IntoBoxed<'a, game::table, Sqlite> {
+  // Each whitelisted tag is its own subquery, since a game needs all of them.
+  for tag_name in whitelist {
+    q = q.filter(game::id.eq_any(get_filter_ids_query(vec![tag_name.clone()])));
+  }
+  if !blacklist.is_empty() {
+    q = q.filter(game::id.ne_all(get_filter_ids_query(blacklist.to_vec())));
+  }
+  q
+}
 
 /// if pageSize is Some, order_by_ascending will be treated as the key.
 fn get_game_query<'a>(
   filters: &FilterOpts,
+  exclusions: &GameExclusions,
   order_by_ascending: Option<(&GameRelation, bool)>,
   offset: Option<i64>,
   page_size: Option<i64>,
 ) -> diesel::helper_types::IntoBoxed<'a, game::table, Sqlite> {
-  let mut query = game::table.into_boxed();
-  if let Some(_playlist_id) = &filters.playlist_id {
-    todo!()
+  let mut query = apply_game_exclusions(game::table.into_boxed(), exclusions, filters.curate);
+  if let Some(playlist_id) = &filters.playlist_id {
+    // Join through playlist_game as a subquery so the query stays a boxed game::table, which is
+    // what the GameRelation filters and pagination work on.
+    query = query.filter(
+      game::id.nullable().eq_any(
+        playlist_game::table
+          .filter(playlist_game::playlistId.eq(playlist_id.clone()))
+          .select(playlist_game::gameId),
+      ),
+    );
+    if order_by_ascending.is_none() {
+      // The position of the current game row within the playlist.
+      let playlist_order =
+        sql::<Integer>(r#"(SELECT pg."order" FROM playlist_game pg WHERE pg.playlistId = "#)
+          .bind::<Text, _>(playlist_id.clone())
+          .sql(" AND pg.gameId = game.id)");
+      query = query.order(playlist_order.asc());
+      if let Some(page_size) = page_size {
+        query = query.limit(page_size);
+      }
+    }
   }
   if let Some(search) = &filters.search_query {
     // Whitelist tends to be more restrictive, do it first.
@@ -106,6 +397,7 @@ fn get_game_query<'a>(
     for filter in &search.generic_blacklist {
       query = apply_generic_filter(query, filter, false);
     }
+    query = apply_tag_filters(query, &search.tag_whitelist, &search.tag_blacklist);
   }
   if let Some((order_by, ascending)) = order_by_ascending {
     if let Some(page_size) = page_size {
@@ -149,3 +441,294 @@ where
     )
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::Playlist;
+  use crate::playlist::replace_playlist;
+  use crate::types::{AddAppKind, ParsedSearch};
+  use crate::{test_game, test_state};
+
+  fn playlist_filter(search_query: Option<ParsedSearch>) -> FilterOpts {
+    FilterOpts {
+      search_limit: None,
+      playlist_id: Some("p1".to_string()),
+      search_query,
+      curate: false,
+    }
+  }
+
+  fn titles(games: Vec<Game>) -> Vec<String> {
+    games.into_iter().map(|g| g.title).collect()
+  }
+
+  #[test]
+  fn playlist_scoped_query() {
+    let state = test_state();
+    for (id, title) in [
+      ("a", "Alpha"),
+      ("b", "Bravo"),
+      ("c", "Charlie"),
+      ("d", "Delta"),
+    ] {
+      save_game(&state, test_game(id, title)).unwrap();
+    }
+    replace_playlist(
+      &state,
+      Playlist {
+        id: "p1".to_string(),
+        title: "Mix".to_string(),
+        description: String::new(),
+        author: String::new(),
+        icon: None,
+        library: "arcade".to_string(),
+        extreme: false,
+      },
+      ["c", "a", "b"]
+        .iter()
+        .map(|id| (id.to_string(), String::new()))
+        .collect(),
+    )
+    .unwrap();
+
+    let games = get_game_query(
+      &playlist_filter(None),
+      &GameExclusions::default(),
+      None,
+      None,
+      None,
+    )
+    .load::<Game>(&mut *state.write().unwrap())
+    .unwrap();
+    assert_eq!(titles(games), vec!["Charlie", "Alpha", "Bravo"]);
+
+    let search = ParsedSearch {
+      blacklist: vec![GameRelation::title("Alpha".to_string())],
+      ..Default::default()
+    };
+    let games = get_game_query(
+      &playlist_filter(Some(search)),
+      &GameExclusions::default(),
+      None,
+      None,
+      None,
+    )
+    .load::<Game>(&mut *state.write().unwrap())
+    .unwrap();
+    assert_eq!(titles(games), vec!["Charlie", "Bravo"]);
+
+    // Keyset paging by title still applies inside the playlist.
+    let key = GameRelation::title("Alpha".to_string());
+    let games = get_game_query(
+      &playlist_filter(None),
+      &GameExclusions::default(),
+      Some((&key, true)),
+      None,
+      Some(5),
+    )
+    .load::<Game>(&mut *state.write().unwrap())
+    .unwrap();
+    assert_eq!(titles(games), vec!["Bravo", "Charlie"]);
+  }
+
+  #[test]
+  fn search_pages_through_duplicate_keys() {
+    let state = test_state();
+    for i in 0..7 {
+      let mut g = test_game(&format!("g{}", i), &format!("Game {}", i));
+      g.developer = if i < 5 { "Same Dev" } else { "Zed" }.to_string();
+      save_game(&state, g).unwrap();
+    }
+
+    let order = Some((GameRelation::developer(String::new()), true));
+    let mut cursor = None;
+    let mut seen = vec![];
+    loop {
+      let filters = FilterOpts {
+        search_limit: Some(2),
+        ..Default::default()
+      };
+      let page = search_games(&state, filters, order.clone(), cursor).unwrap();
+      assert_eq!(page.total, 7);
+      seen.extend(page.games.into_iter().map(|g| g.id));
+      cursor = page.next_cursor;
+      if cursor.is_none() {
+        break;
+      }
+    }
+    let expected: Vec<String> = (0..7).map(|i| format!("g{}", i)).collect();
+    assert_eq!(seen, expected);
+
+    // A cursor only works with the order it came from.
+    let filters = || FilterOpts {
+      search_limit: Some(2),
+      ..Default::default()
+    };
+    let cursor = search_games(&state, filters(), order, None)
+      .unwrap()
+      .next_cursor;
+    let title_order = Some((GameRelation::title(String::new()), true));
+    assert!(matches!(
+      search_games(&state, filters(), title_order, cursor),
+      Err(DbErrors::InvalidArgument(_))
+    ));
+  }
+
+  #[test]
+  fn tag_filters_match_any_alias() {
+    use crate::schema::game_tags_tag;
+    use crate::tag::{add_alias_to_tag, create_tag, InsertableTag};
+
+    let state = test_state();
+    let mut tag_ids = vec![];
+    for name in ["Puzzle", "Gore"] {
+      let (t, _) = create_tag(
+        &state,
+        InsertableTag {
+          name: name.to_string(),
+          category_id: None,
+          description: None,
+        },
+      )
+      .unwrap();
+      tag_ids.push(t.id);
+    }
+    add_alias_to_tag(&state, tag_ids[0], "Logic".to_string()).unwrap();
+    for (id, tags) in [("a", vec![0]), ("b", vec![0, 1]), ("c", vec![])] {
+      save_game(&state, test_game(id, id)).unwrap();
+      for t in tags {
+        diesel::insert_into(game_tags_tag::table)
+          .values((
+            game_tags_tag::gameId.eq(id),
+            game_tags_tag::tagId.eq(tag_ids[t]),
+          ))
+          .execute(&mut *state.write().unwrap())
+          .unwrap();
+      }
+    }
+
+    let search = |state: &DbState, query: &str| {
+      let filters = FilterOpts {
+        search_query: Some(crate::search::parse_search(query).unwrap()),
+        ..Default::default()
+      };
+      let page = search_games(state, filters, None, None).unwrap();
+      titles(page.games)
+    };
+    assert_eq!(search(&state, "#logic"), vec!["a", "b"]);
+    assert_eq!(search(&state, "#Puzzle -#Gore"), vec!["a"]);
+    assert_eq!(search(&state, "-tag:gore"), vec!["a", "c"]);
+  }
+
+  #[test]
+  fn random_games_skip_excluded() {
+    let state = test_state();
+    for i in 0..10 {
+      let mut g = test_game(&format!("g{}", i), &format!("Game {}", i));
+      if i < 4 {
+        g.library = "theatre".to_string();
+      }
+      g.extreme = i == 9;
+      save_game(&state, g).unwrap();
+    }
+    *state.exclusions.write().unwrap() = GameExclusions {
+      hide_extreme: true,
+      excluded_random_libraries: vec!["theatre".to_string()],
+      ..Default::default()
+    };
+
+    // Oversized requests are capped rather than refused.
+    let opts = RandomGameOpts {
+      count: usize::MAX,
+      ..Default::default()
+    };
+    let mut ids: Vec<String> = find_random_games(&state, opts)
+      .unwrap()
+      .into_iter()
+      .map(|g| g.id)
+      .collect();
+    ids.sort();
+    assert_eq!(ids, vec!["g4", "g5", "g6", "g7", "g8"]);
+
+    let opts = RandomGameOpts {
+      count: 2,
+      search_query: Some(crate::search::parse_search("title:\"Game 5\"").unwrap()),
+      ..Default::default()
+    };
+    let games = find_random_games(&state, opts).unwrap();
+    assert_eq!(titles(games), vec!["Game 5"]);
+  }
+
+  #[test]
+  fn add_apps_crud_and_order() {
+    let state = test_state();
+    save_game(&state, test_game("g", "Game")).unwrap();
+    let new_app = |name: &str, path: &str, auto_run_before: bool| InsertableAddApp {
+      parent_game_id: "g".to_string(),
+      name: name.to_string(),
+      application_path: path.to_string(),
+      launch_command: "readme.txt".to_string(),
+      auto_run_before,
+      wait_for_exit: false,
+    };
+    let b = create_add_app(&state, new_app("B Setup", "setup.exe", true)).unwrap();
+    create_add_app(&state, new_app("A Setup", "setup.exe", true)).unwrap();
+    let extras = create_add_app(&state, new_app("Extras", ":extras:", false)).unwrap();
+    assert!(create_add_app(
+      &state,
+      InsertableAddApp {
+        parent_game_id: "missing".to_string(),
+        ..new_app("Orphan", ":message:", false)
+      }
+    )
+    .is_err());
+
+    let names =
+      |apps: Vec<AdditionalApp>| -> Vec<String> { apps.into_iter().map(|a| a.name).collect() };
+    assert_eq!(
+      names(find_auto_run_before_add_apps(&state, "g".to_string()).unwrap()),
+      vec!["A Setup", "B Setup"]
+    );
+    assert_eq!(
+      extras.kind(),
+      AddAppKind::Extras {
+        folder: "readme.txt".to_string()
+      }
+    );
+
+    save_add_app(
+      &state,
+      AdditionalApp {
+        auto_run_before: false,
+        ..b
+      },
+    )
+    .unwrap();
+    assert_eq!(
+      names(find_auto_run_before_add_apps(&state, "g".to_string()).unwrap()),
+      vec!["A Setup"]
+    );
+    remove_add_app(&state, extras.id).unwrap();
+    assert_eq!(find_add_apps(&state, "g".to_string()).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn missing_rows_are_not_found_errors() {
+    let state = test_state();
+    match find_game(&state, "missing".to_string()) {
+      Err(DbErrors::NotFound { entity, key }) => {
+        assert_eq!(entity, "game");
+        assert_eq!(key, "missing");
+      }
+      r => panic!("expected a not found error, got {:?}", r.map(|g| g.id)),
+    }
+    assert!(matches!(
+      find_games_with_tag(&state, "No such tag".to_string()),
+      Err(DbErrors::NotFound {
+        entity: "tag alias",
+        ..
+      })
+    ));
+  }
+}
@@ -0,0 +1,262 @@
+use crate::last_insert_rowid;
+use crate::models::{Game, GameData};
+use crate::schema::{game, game_data};
+use crate::types::{DbErrors, DbState, OrNotFound};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::Bool;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Insertable)]
+#[diesel(table_name = game_data)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertableGameData {
+  #[diesel(column_name = gameId)]
+  pub game_id: String,
+  pub title: String,
+  pub sha256: String,
+  pub crc32: i32,
+  pub size: i32,
+  #[serde(default)]
+  #[diesel(column_name = presentOnDisk)]
+  pub present_on_disk: bool,
+  pub path: Option<String>,
+  pub parameters: Option<String>,
+}
+
+/// The on-disk status to record for a single data pack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameDataPresence {
+  pub game_data_id: i32,
+  pub present_on_disk: bool,
+  pub path: Option<String>,
+}
+
+pub fn find_all_game_data(state: &DbState) -> Result<Vec<GameData>, DbErrors> {
+  let conn = &mut state.read()?;
+  Ok(game_data::table.order(game_data::id.asc()).load(conn)?)
+}
+
+/// Loads every data pack of a game, oldest first.
+pub fn find_game_data(state: &DbState, game_id: String) -> Result<Vec<GameData>, DbErrors> {
+  let conn = &mut state.read()?;
+  Ok(
+    game_data::table
+      .filter(game_data::gameId.eq(game_id))
+      .order((game_data::dateAdded.asc(), game_data::id.asc()))
+      .load(conn)?,
+  )
+}
+
+pub fn find_game_data_by_id(state: &DbState, game_data_id: i32) -> Result<GameData, DbErrors> {
+  let conn = &mut state.read()?;
+  game_data::table
+    .find(game_data_id)
+    .first(conn)
+    .or_not_found("game data", game_data_id)
+}
+
+/// Records a new data pack for a game. It becomes the game's active data if it had none.
+pub fn import_game_data(
+  state: &DbState,
+  new_data: InsertableGameData,
+) -> Result<GameData, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let g = game::table
+      .find(&new_data.game_id)
+      .first::<Game>(conn)
+      .or_not_found("game", &new_data.game_id)?;
+    diesel::insert_into(game_data::table)
+      .values((
+        &new_data,
+        game_data::dateAdded.eq(chrono::Utc::now().naive_utc()),
+      ))
+      .execute(conn)?;
+    let data_id = diesel::select(last_insert_rowid()).get_result::<i32>(conn)?;
+    if g.active_data_id.is_none() {
+      diesel::update(game::table.find(&g.id))
+        .set((
+          game::activeDataId.eq(data_id),
+          game::activeDataOnDisk.eq(new_data.present_on_disk),
+        ))
+        .execute(conn)?;
+    }
+    Ok(game_data::table.find(data_id).first(conn)?)
+  })
+}
+
+/// Makes one of a game's data packs the active one, or clears it with None.
+pub fn set_active_game_data(
+  state: &DbState,
+  game_id: String,
+  game_data_id: Option<i32>,
+) -> Result<Game, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let on_disk = match game_data_id {
+      Some(id) => {
+        let data = game_data::table
+          .find(id)
+          .first::<GameData>(conn)
+          .or_not_found("game data", id)?;
+        if data.game_id.as_deref() != Some(game_id.as_str()) {
+          return Err(DbErrors::ConstraintViolation(format!(
+            "game data {} does not belong to game {}",
+            id, game_id
+          )));
+        }
+        data.present_on_disk
+      }
+      None => false,
+    };
+    let updated = diesel::update(game::table.find(&game_id))
+      .set((
+        game::activeDataId.eq(game_data_id),
+        game::activeDataOnDisk.eq(on_disk),
+      ))
+      .execute(conn)?;
+    if updated == 0 {
+      return Err(DbErrors::NotFound {
+        entity: "game",
+        key: game_id,
+      });
+    }
+    Ok(game::table.find(&game_id).first(conn)?)
+  })
+}
+
+/// Marks a data pack as present on disk at `path`, or as absent when `path` is None.
+pub fn set_game_data_present(
+  state: &DbState,
+  game_data_id: i32,
+  path: Option<String>,
+) -> Result<GameData, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let presence = GameDataPresence {
+      game_data_id,
+      present_on_disk: path.is_some(),
+      path,
+    };
+    if write_presence(conn, &[presence])? == 0 {
+      return Err(DbErrors::NotFound {
+        entity: "game data",
+        key: game_data_id.to_string(),
+      });
+    }
+    Ok(game_data::table.find(game_data_id).first(conn)?)
+  })
+}
+
+/// Records the on-disk status of many data packs at once, e.g. after scanning the data packs
+/// folder. Returns the number of data packs updated.
+pub fn update_game_data_presence(
+  state: &DbState,
+  presence: Vec<GameDataPresence>,
+) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| Ok(write_presence(conn, &presence)?))
+}
+
+fn write_presence(
+  conn: &mut SqliteConnection,
+  presence: &[GameDataPresence],
+) -> Result<usize, Error> {
+  let mut updated = 0;
+  for p in presence {
+    updated += diesel::update(game_data::table.find(p.game_data_id))
+      .set((
+        game_data::presentOnDisk.eq(p.present_on_disk),
+        game_data::path.eq(&p.path),
+      ))
+      .execute(conn)?;
+  }
+  let ids: Vec<i32> = presence.iter().map(|p| p.game_data_id).collect();
+  refresh_active_data_on_disk(conn, &ids)?;
+  Ok(updated)
+}
+
+/// Copies `presentOnDisk` of the given data packs onto the games using them as active data.
+/// Must be called whenever a data pack's on-disk status changes.
+pub(crate) fn refresh_active_data_on_disk(
+  conn: &mut SqliteConnection,
+  game_data_ids: &[i32],
+) -> Result<(), Error> {
+  for chunk in game_data_ids.chunks(500) {
+    diesel::update(game::table.filter(game::activeDataId.eq_any(chunk)))
+      .set(game::activeDataOnDisk.eq(sql::<Bool>(
+        "(SELECT gd.presentOnDisk FROM game_data gd WHERE gd.id = game.activeDataId)",
+      )))
+      .execute(conn)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::{find_game, save_game};
+  use crate::{test_game, test_state};
+
+  fn new_data(sha256: &str) -> InsertableGameData {
+    InsertableGameData {
+      game_id: "g".to_string(),
+      title: "Data".to_string(),
+      sha256: sha256.to_string(),
+      crc32: 0,
+      size: 10,
+      present_on_disk: false,
+      path: None,
+      parameters: None,
+    }
+  }
+
+  #[test]
+  fn active_data_stays_in_sync() {
+    let state = test_state();
+    save_game(&state, test_game("g", "Game")).unwrap();
+    let first = import_game_data(&state, new_data("aa")).unwrap();
+    let second = import_game_data(&state, new_data("bb")).unwrap();
+    assert!(import_game_data(
+      &state,
+      InsertableGameData {
+        game_id: "missing".to_string(),
+        ..new_data("cc")
+      }
+    )
+    .is_err());
+
+    let g = find_game(&state, "g".to_string()).unwrap();
+    assert_eq!(g.active_data_id, Some(first.id));
+    assert!(!g.active_data_on_disk);
+
+    set_game_data_present(&state, second.id, Some("bb.zip".to_string())).unwrap();
+    let g = set_active_game_data(&state, "g".to_string(), Some(second.id)).unwrap();
+    assert!(g.active_data_on_disk);
+
+    let absent = set_game_data_present(&state, second.id, None).unwrap();
+    assert_eq!(absent.path, None);
+    let g = find_game(&state, "g".to_string()).unwrap();
+    assert!(!g.active_data_on_disk);
+
+    let updated = update_game_data_presence(
+      &state,
+      vec![GameDataPresence {
+        game_data_id: first.id,
+        present_on_disk: true,
+        path: Some("g-1.zip".to_string()),
+      }],
+    )
+    .unwrap();
+    assert_eq!(updated, 1);
+    let g = find_game(&state, "g".to_string()).unwrap();
+    assert_eq!(g.active_data_id, Some(second.id));
+    assert!(!g.active_data_on_disk);
+    let g = set_active_game_data(&state, "g".to_string(), Some(first.id)).unwrap();
+    assert!(g.active_data_on_disk);
+
+    assert_eq!(find_game_data(&state, "g".to_string()).unwrap().len(), 2);
+  }
+}
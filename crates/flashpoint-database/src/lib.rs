@@ -1,13 +1,25 @@
 use diesel::prelude::*;
 
-// TODO: make all the functions return Result enums instead of expect()ing.
+pub mod filter;
 pub mod game;
+pub mod game_data;
+pub mod migrations;
+pub mod misc;
 pub mod models;
 //pub mod models_expanded;
+pub mod playlist;
 pub mod schema;
+pub mod search;
+pub mod source;
 pub mod tag;
 pub mod types;
-use types::{DbErrors, DbState};
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use types::{DbErrors, DbOptions, DbState};
+
+sql_function!(fn last_insert_rowid() -> diesel::sql_types::Integer);
 
 fn establish_connection(db_path: &str) -> Result<SqliteConnection, DbErrors> {
   match SqliteConnection::establish(db_path) {
@@ -16,7 +28,175 @@ fn establish_connection(db_path: &str) -> Result<SqliteConnection, DbErrors> {
   }
 }
 
-pub fn initialize(db_path: &str) -> Result<DbState, DbErrors> {
-  let conn = establish_connection(db_path)?;
-  Ok(DbState { conn })
+/// Applied to every connection as it's opened.
+#[derive(Debug)]
+struct ConnectionOptions {
+  busy_timeout: Duration,
+}
+
+impl ConnectionOptions {
+  fn apply(&self, conn: &mut SqliteConnection) -> Result<(), diesel::result::Error> {
+    conn.batch_execute(&format!(
+      "PRAGMA busy_timeout = {};",
+      self.busy_timeout.as_millis()
+    ))
+  }
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+  fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+    self.apply(conn).map_err(diesel::r2d2::Error::QueryError)
+  }
+}
+
+/// Opens the database at `db_path`, creating it if needed, and applies any pending migrations.
+/// The database is then switched to WAL mode so reads don't wait on writes. A database whose
+/// schema is refused is left as it was.
+pub fn initialize(db_path: &str, opts: &DbOptions) -> Result<DbState, DbErrors> {
+  let conn_opts = ConnectionOptions {
+    busy_timeout: opts.busy_timeout,
+  };
+  let mut writer = establish_connection(db_path)?;
+  conn_opts.apply(&mut writer)?;
+  // The journal mode is stored in the file, so only switch once we know the schema is ours.
+  migrations::run_pending_migrations(&mut writer)?;
+  writer.batch_execute("PRAGMA journal_mode = WAL;")?;
+
+  let readers = Pool::builder()
+    .max_size(opts.pool_size.max(1))
+    .min_idle(Some(1))
+    .connection_customizer(Box::new(conn_opts))
+    .build(ConnectionManager::<SqliteConnection>::new(db_path))
+    .map_err(DbErrors::Pool)?;
+  Ok(DbState {
+    readers,
+    writer: Mutex::new(writer),
+    exclusions: RwLock::new(Default::default()),
+  })
+}
+
+/// A fresh in-memory database.
+#[cfg(any(test, feature = "test-util"))]
+pub fn test_state() -> DbState {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+  // Plain `:memory:` would give every pooled connection its own empty database.
+  let db_path = format!(
+    "file:test_db_{}?mode=memory&cache=shared",
+    NEXT_DB.fetch_add(1, Ordering::Relaxed)
+  );
+  initialize(&db_path, &DbOptions::default()).expect("Failed to open in-memory database")
+}
+
+/// A playable Flash game with every other field left empty.
+#[cfg(any(test, feature = "test-util"))]
+pub fn test_game(id: &str, title: &str) -> models::Game {
+  let now = chrono::Utc::now().naive_utc();
+  models::Game {
+    id: id.to_string(),
+    parent_game_id: None,
+    title: title.to_string(),
+    alternate_titles: String::new(),
+    series: String::new(),
+    developer: String::new(),
+    publisher: String::new(),
+    date_added: now,
+    date_modified: now,
+    platform: "Flash".to_string(),
+    broken: false,
+    extreme: false,
+    play_mode: "Single Player".to_string(),
+    status: "Playable".to_string(),
+    notes: String::new(),
+    source: String::new(),
+    application_path: String::new(),
+    launch_command: String::new(),
+    release_date: String::new(),
+    version: String::new(),
+    original_description: String::new(),
+    language: "en".to_string(),
+    library: "arcade".to_string(),
+    order_title: title.to_lowercase(),
+    active_data_id: None,
+    active_data_on_disk: false,
+    tags_str: String::new(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_run_alongside_a_write() {
+    let path = std::env::temp_dir().join(format!("fp_db_pool_{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let state = initialize(
+      path.to_str().unwrap(),
+      &DbOptions {
+        pool_size: 2,
+        busy_timeout: Duration::from_millis(500),
+      },
+    )
+    .unwrap();
+    game::save_game(&state, test_game("a", "A")).unwrap();
+
+    {
+      // Both readers are out of the pool while a write goes through.
+      let mut first = state.read().unwrap();
+      let _second = state.read().unwrap();
+      let mode = diesel::sql_query("PRAGMA journal_mode")
+        .get_result::<JournalMode>(&mut first)
+        .unwrap();
+      assert_eq!(mode.journal_mode, "wal");
+      game::save_game(&state, test_game("b", "B")).unwrap();
+    }
+
+    std::thread::scope(|s| {
+      let handles: Vec<_> = ["a", "b"]
+        .into_iter()
+        .map(|id| s.spawn(|| game::find_game(&state, id.to_string()).unwrap().id))
+        .collect();
+      let ids: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+      assert_eq!(ids, vec!["a", "b"]);
+    });
+
+    drop(state);
+    for suffix in ["", "-wal", "-shm"] {
+      let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+  }
+
+  #[test]
+  fn refused_databases_keep_their_journal_mode() {
+    let path = std::env::temp_dir().join(format!("fp_db_refused_{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut conn = establish_connection(path.to_str().unwrap()).unwrap();
+    conn
+      .batch_execute("CREATE TABLE stuff (id integer);")
+      .unwrap();
+    drop(conn);
+
+    let opts = DbOptions {
+      pool_size: 1,
+      busy_timeout: Duration::from_millis(500),
+    };
+    assert!(matches!(
+      initialize(path.to_str().unwrap(), &opts),
+      Err(DbErrors::UnrecognizedSchema(_))
+    ));
+    let mut conn = establish_connection(path.to_str().unwrap()).unwrap();
+    let mode = diesel::sql_query("PRAGMA journal_mode")
+      .get_result::<JournalMode>(&mut conn)
+      .unwrap();
+    assert_eq!(mode.journal_mode, "delete");
+    drop(conn);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[derive(QueryableByName)]
+  struct JournalMode {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    journal_mode: String,
+  }
 }
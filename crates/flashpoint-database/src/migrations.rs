@@ -0,0 +1,247 @@
+//! Schema migrations, embedded in the binary. Applied migrations are recorded in diesel's own
+//! `__diesel_schema_migrations` table, so the diesel CLI agrees with us about the schema version.
+
+use crate::types::DbErrors;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+
+diesel::table! {
+  __diesel_schema_migrations (version) {
+    version -> Text,
+    run_on -> Timestamp,
+  }
+}
+
+struct Migration {
+  /// The migration folder name without the description, and without dashes like diesel does.
+  version: &'static str,
+  up: &'static str,
+}
+
+/// Every migration in `migrations/`, oldest first.
+const MIGRATIONS: &[Migration] = &[Migration {
+  version: "20221215110819",
+  up: include_str!("../migrations/2022-12-15-110819_initial/up.sql"),
+}];
+
+/// The legacy launcher's TypeORM migrations table.
+const TYPEORM_MIGRATIONS_TABLE: &str = "migrations";
+
+/// The last TypeORM migration our initial migration matches, as `(name, timestamp)`. A legacy
+/// database whose latest migration is newer has a schema we don't know.
+const TYPEORM_LATEST: (&str, i64) = ("GameDataParams1619885915109", 1619885915109);
+
+/// Tables a legacy database must have before we adopt it.
+const LEGACY_REQUIRED_TABLES: &[&str] = &["game", "tag", "tag_alias", "playlist"];
+
+#[derive(QueryableByName)]
+struct TableName {
+  #[diesel(sql_type = Text)]
+  name: String,
+}
+
+#[derive(QueryableByName)]
+struct TypeormMigration {
+  #[diesel(sql_type = BigInt)]
+  timestamp: i64,
+  #[diesel(sql_type = Text)]
+  name: String,
+}
+
+/// The latest schema version this build knows about.
+pub fn latest_schema_version() -> &'static str {
+  MIGRATIONS[MIGRATIONS.len() - 1].version
+}
+
+/// Brings the schema up to date, returning the versions that were applied. An empty database gets
+/// every migration, and a legacy launcher database is adopted as being at the initial migration.
+/// Fails without touching anything if the database has a migration we don't know about, ours or
+/// the legacy launcher's.
+pub fn run_pending_migrations(conn: &mut SqliteConnection) -> Result<Vec<&'static str>, DbErrors> {
+  let tables = load_table_names(conn)?;
+  let has_table = |name: &str| tables.iter().any(|t| t == name);
+
+  if !has_table("__diesel_schema_migrations") {
+    if has_table(TYPEORM_MIGRATIONS_TABLE) {
+      if let Some(missing) = LEGACY_REQUIRED_TABLES.iter().find(|t| !has_table(t)) {
+        return Err(DbErrors::UnrecognizedSchema(format!(
+          "legacy database is missing the {} table",
+          missing
+        )));
+      }
+      if let Some(newest) = load_latest_typeorm_migration(conn)? {
+        if newest.timestamp > TYPEORM_LATEST.1 {
+          return Err(DbErrors::UnknownSchemaVersion {
+            found: newest.name,
+            latest: TYPEORM_LATEST.0,
+          });
+        }
+      }
+    } else if !tables.is_empty() {
+      return Err(DbErrors::UnrecognizedSchema(
+        "database has tables but no migration history".to_string(),
+      ));
+    }
+    conn.transaction(|conn| {
+      create_migrations_table(conn)?;
+      if has_table(TYPEORM_MIGRATIONS_TABLE) {
+        record_migration(conn, MIGRATIONS[0].version)?;
+      }
+      Ok::<_, diesel::result::Error>(())
+    })?;
+  }
+
+  let applied: Vec<String> = __diesel_schema_migrations::table
+    .select(__diesel_schema_migrations::version)
+    .load(conn)?;
+  if let Some(unknown) = applied
+    .iter()
+    .filter(|v| !MIGRATIONS.iter().any(|m| &m.version == v))
+    .max()
+  {
+    return Err(DbErrors::UnknownSchemaVersion {
+      found: unknown.clone(),
+      latest: latest_schema_version(),
+    });
+  }
+
+  let mut ran = vec![];
+  for m in MIGRATIONS
+    .iter()
+    .filter(|m| !applied.iter().any(|v| v == m.version))
+  {
+    conn.transaction(|conn| {
+      conn.batch_execute(m.up)?;
+      record_migration(conn, m.version)
+    })?;
+    ran.push(m.version);
+  }
+  Ok(ran)
+}
+
+fn load_table_names(conn: &mut SqliteConnection) -> Result<Vec<String>, diesel::result::Error> {
+  let tables = diesel::sql_query(
+    "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+  )
+  .load::<TableName>(conn)?;
+  Ok(tables.into_iter().map(|t| t.name).collect())
+}
+
+fn load_latest_typeorm_migration(
+  conn: &mut SqliteConnection,
+) -> Result<Option<TypeormMigration>, diesel::result::Error> {
+  diesel::sql_query(format!(
+    "SELECT timestamp, name FROM {} ORDER BY timestamp DESC LIMIT 1",
+    TYPEORM_MIGRATIONS_TABLE
+  ))
+  .load::<TypeormMigration>(conn)
+  .map(|rows| rows.into_iter().next())
+}
+
+fn create_migrations_table(conn: &mut SqliteConnection) -> Result<(), diesel::result::Error> {
+  conn.batch_execute(
+    "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+       version VARCHAR(50) PRIMARY KEY NOT NULL,
+       run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+     );",
+  )
+}
+
+fn record_migration(
+  conn: &mut SqliteConnection,
+  version: &str,
+) -> Result<(), diesel::result::Error> {
+  diesel::insert_into(__diesel_schema_migrations::table)
+    .values(__diesel_schema_migrations::version.eq(version))
+    .execute(conn)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn conn() -> SqliteConnection {
+    SqliteConnection::establish(":memory:").unwrap()
+  }
+
+  #[test]
+  fn fresh_database_gets_every_migration() {
+    let mut conn = conn();
+    assert_eq!(
+      run_pending_migrations(&mut conn).unwrap(),
+      vec![latest_schema_version()]
+    );
+    assert!(load_table_names(&mut conn)
+      .unwrap()
+      .contains(&"game".to_string()));
+    assert!(run_pending_migrations(&mut conn).unwrap().is_empty());
+  }
+
+  #[test]
+  fn legacy_database_is_adopted() {
+    let mut conn = conn();
+    conn.batch_execute(MIGRATIONS[0].up).unwrap();
+    conn
+      .batch_execute(
+        "CREATE TABLE migrations (id integer PRIMARY KEY, timestamp bigint, name varchar);
+         INSERT INTO migrations (timestamp, name) VALUES
+           (1593172736527, 'Initial1593172736527'),
+           (1619885915109, 'GameDataParams1619885915109');
+         INSERT INTO game (id, title, alternateTitles, series, developer, publisher, dateAdded,
+           dateModified, platform, broken, extreme, playMode, status, notes, source,
+           applicationPath, launchCommand, releaseDate, version, originalDescription, language,
+           library, orderTitle, tagsStr)
+         VALUES ('a', 'A', '', '', '', '', '2020-01-01', '2020-01-01', 'Flash', 0, 0, '', '', '',
+           '', '', '', '', '', '', '', 'arcade', 'a', '');",
+      )
+      .unwrap();
+    let ran = run_pending_migrations(&mut conn).unwrap();
+    assert!(!ran.contains(&MIGRATIONS[0].version));
+    let count: i64 = crate::schema::game::table
+      .count()
+      .get_result(&mut conn)
+      .unwrap();
+    assert_eq!(count, 1);
+  }
+
+  #[test]
+  fn newer_or_unknown_schemas_are_refused() {
+    let mut conn = conn();
+    run_pending_migrations(&mut conn).unwrap();
+    record_migration(&mut conn, "99991231000000").unwrap();
+    assert!(matches!(
+      run_pending_migrations(&mut conn),
+      Err(DbErrors::UnknownSchemaVersion { found, .. }) if found == "99991231000000"
+    ));
+
+    let mut conn = self::conn();
+    conn.batch_execute(MIGRATIONS[0].up).unwrap();
+    conn
+      .batch_execute(
+        "CREATE TABLE migrations (id integer PRIMARY KEY, timestamp bigint, name varchar);
+         INSERT INTO migrations (timestamp, name) VALUES
+           (1619885915109, 'GameDataParams1619885915109'),
+           (1676712700000, 'ChildCurations1676712700000');",
+      )
+      .unwrap();
+    assert!(matches!(
+      run_pending_migrations(&mut conn),
+      Err(DbErrors::UnknownSchemaVersion { found, .. }) if found == "ChildCurations1676712700000"
+    ));
+    // Nothing was recorded, so a newer launcher can still open it.
+    assert!(!load_table_names(&mut conn)
+      .unwrap()
+      .contains(&"__diesel_schema_migrations".to_string()));
+
+    let mut conn = self::conn();
+    conn
+      .batch_execute("CREATE TABLE stuff (id integer);")
+      .unwrap();
+    assert!(matches!(
+      run_pending_migrations(&mut conn),
+      Err(DbErrors::UnrecognizedSchema(_))
+    ));
+  }
+}
@@ -1,7 +1,154 @@
+use crate::filter::apply_game_exclusions;
+use crate::models::GameRelation;
+use crate::schema::game;
+use crate::types::{DbErrors, DbState};
+use diesel::dsl::count_star;
+use diesel::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// A distinct value of a game column, along with the number of games that have it.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UniqueValue {
+  pub value: String,
+  pub count: i64,
+}
+
 // find_platform_app_paths
 
-// find_unique_values (Probably need to split)
+/// Lists every distinct value of a game column, sorted ignoring case. Only developer, publisher,
+/// series, platform, library, playMode, status and language are supported. Values holding several
+/// entries separated by `;` are split up.
+pub fn find_unique_values(
+  state: &DbState,
+  column: &GameRelation,
+  library: Option<String>,
+) -> Result<Vec<String>, DbErrors> {
+  let mut values: Vec<String> = load_unique_values(state, column, library)?
+    .into_keys()
+    .collect();
+  values.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()).then(a.cmp(b)));
+  Ok(values)
+}
+
+/// Like `find_unique_values`, but with how many games have each value, most used first.
+pub fn find_unique_values_in_order(
+  state: &DbState,
+  column: &GameRelation,
+  library: Option<String>,
+) -> Result<Vec<UniqueValue>, DbErrors> {
+  let mut values: Vec<UniqueValue> = load_unique_values(state, column, library)?
+    .into_iter()
+    .map(|(value, count)| UniqueValue { value, count })
+    .collect();
+  values.sort_by(|a, b| {
+    b.count
+      .cmp(&a.count)
+      .then_with(|| a.value.to_lowercase().cmp(&b.value.to_lowercase()))
+      .then_with(|| a.value.cmp(&b.value))
+  });
+  Ok(values)
+}
+
+pub fn find_platforms(state: &DbState, library: Option<String>) -> Result<Vec<String>, DbErrors> {
+  find_unique_values(state, &GameRelation::platform(String::new()), library)
+}
+
+/// Counts the games for each split value of the column. Hidden games aren't counted.
+fn load_unique_values(
+  state: &DbState,
+  column: &GameRelation,
+  library: Option<String>,
+) -> Result<HashMap<String, i64>, DbErrors> {
+  let conn = &mut state.read()?;
+  let mut visible = apply_game_exclusions(game::table.into_boxed(), &state.exclusions(), false);
+  if let Some(library) = library {
+    visible = visible.filter(game::library.eq(library));
+  }
+  let visible = visible.select(game::id);
+  // Each arm groups by a different column, so the query can't be built once and reused.
+  macro_rules! group_by_column {
+    ($col:expr) => {
+      game::table
+        .filter(game::id.eq_any(visible))
+        .group_by($col)
+        .select(($col, count_star()))
+        .load::<(String, i64)>(conn)?
+    };
+  }
+  let rows = match column {
+    GameRelation::developer(_) => group_by_column!(game::developer),
+    GameRelation::publisher(_) => group_by_column!(game::publisher),
+    GameRelation::series(_) => group_by_column!(game::series),
+    GameRelation::platform(_) => group_by_column!(game::platform),
+    GameRelation::library(_) => group_by_column!(game::library),
+    GameRelation::play_mode(_) => group_by_column!(game::playMode),
+    GameRelation::status(_) => group_by_column!(game::status),
+    GameRelation::language(_) => group_by_column!(game::language),
+    _ => {
+      return Err(DbErrors::InvalidArgument(format!(
+        "no unique values for column {:?}",
+        column
+      )))
+    }
+  };
+
+  let mut values = HashMap::new();
+  for (raw, count) in rows {
+    for value in raw.split(';').map(str::trim).filter(|v| !v.is_empty()) {
+      *values.entry(value.to_string()).or_insert(0) += count;
+    }
+  }
+  Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::save_game;
+  use crate::{test_game, test_state};
 
-// find_unique_values_in_order (Probably need to split)
+  #[test]
+  fn unique_values_are_split() {
+    let state = test_state();
+    for (id, developer, library) in [
+      ("a", "Alice; Bob", "arcade"),
+      ("b", "bob", "arcade"),
+      ("c", "Alice", "theatre"),
+      ("d", "", "arcade"),
+    ] {
+      let mut g = test_game(id, id);
+      g.developer = developer.to_string();
+      g.library = library.to_string();
+      save_game(&state, g).unwrap();
+    }
 
-// find_platforms
+    let developer = GameRelation::from_column_name("developer").unwrap();
+    assert_eq!(
+      find_unique_values(&state, &developer, None).unwrap(),
+      vec!["Alice", "Bob", "bob"]
+    );
+    assert_eq!(
+      find_unique_values_in_order(&state, &developer, Some("arcade".to_string())).unwrap(),
+      vec![
+        UniqueValue {
+          value: "Alice".to_string(),
+          count: 1
+        },
+        UniqueValue {
+          value: "Bob".to_string(),
+          count: 1
+        },
+        UniqueValue {
+          value: "bob".to_string(),
+          count: 1
+        },
+      ]
+    );
+    assert_eq!(find_platforms(&state, None).unwrap(), vec!["Flash"]);
+    assert!(matches!(
+      find_unique_values(&state, &GameRelation::title(String::new()), None),
+      Err(DbErrors::InvalidArgument(_))
+    ));
+  }
+}
@@ -1,11 +1,11 @@
-use crate::schema::game;
+use crate::schema::{additional_app, game, playlist};
 use chrono::NaiveDateTime;
 use diesel::helper_types::IntoBoxed;
 use diesel::prelude::*;
 use diesel::sqlite::Sqlite;
 use diesel::{ExpressionMethods, Insertable, Queryable};
 use flashpoint_macros::TableQueryBuilder;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Queryable, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -19,8 +19,10 @@ pub struct ViewGame {
   pub tags_str: String,
 }
 
-#[derive(TableQueryBuilder, Serialize, Insertable, Queryable, Debug)]
+#[derive(TableQueryBuilder, Insertable, Queryable)]
 #[diesel(table_name = game)]
+#[fp_table_query_builder(inherit_attr_below = true)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Game {
   pub id: String,
@@ -65,19 +67,25 @@ pub struct Game {
   pub tags_str: String,
 }
 
-#[derive(Serialize, Queryable, Debug)]
+#[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset, Clone, PartialEq, Debug)]
+#[diesel(table_name = additional_app, treat_none_as_null = true)]
 #[serde(rename_all = "camelCase")]
 pub struct AdditionalApp {
   pub id: String,
+  #[diesel(column_name = applicationPath)]
   pub application_path: String,
+  #[diesel(column_name = autoRunBefore)]
   pub auto_run_before: bool,
+  #[diesel(column_name = launchCommand)]
   pub launch_command: String,
   pub name: String,
+  #[diesel(column_name = waitForExit)]
   pub wait_for_exit: bool,
+  #[diesel(column_name = parentGameId)]
   pub parent_game_id: Option<String>,
 }
 
-#[derive(Serialize, Queryable, Debug)]
+#[derive(Serialize, Queryable, Clone, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct GameData {
   pub id: i32,
@@ -92,7 +100,8 @@ pub struct GameData {
   pub parameters: Option<String>,
 }
 
-#[derive(Serialize, Queryable, Debug)]
+#[derive(Serialize, Deserialize, Queryable, Insertable, AsChangeset, Clone, Debug)]
+#[diesel(table_name = playlist, treat_none_as_null = true)]
 #[serde(rename_all = "camelCase")]
 pub struct Playlist {
   pub id: String,
@@ -108,7 +117,7 @@ pub struct Playlist {
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistGame {
   pub id: i32,
-  pub playlist_id: Option<String>,
+  pub playlist_id: String,
   pub order: i32,
   pub notes: String,
   pub game_id: Option<String>,
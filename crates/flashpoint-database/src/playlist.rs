@@ -1,19 +1,412 @@
-// find_playlist
+use crate::last_insert_rowid;
+use crate::models::{Playlist, PlaylistGame};
+use crate::schema::{game, playlist, playlist_game};
+use crate::types::{DbErrors, DbState, OrNotFound};
+use diesel::prelude::*;
+use serde::Deserialize;
 
-// find_playlist_by_name
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertablePlaylist {
+  pub title: String,
+  #[serde(default)]
+  pub description: String,
+  #[serde(default)]
+  pub author: String,
+  pub icon: Option<String>,
+  pub library: String,
+  #[serde(default)]
+  pub extreme: bool,
+}
 
-// find_playlists
+/// Narrows down `find_playlists`. Unset fields match every playlist.
+#[derive(Debug, Default, Deserialize)]
+pub struct PlaylistFilter {
+  pub library: Option<String>,
+  pub extreme: Option<bool>,
+}
 
-// remove_playlist
+pub fn find_playlist(state: &DbState, playlist_id: String) -> Result<Playlist, DbErrors> {
+  let conn = &mut state.read()?;
+  playlist::table
+    .find(&playlist_id)
+    .first(conn)
+    .or_not_found("playlist", playlist_id)
+}
 
-// update_playlist
+pub fn find_playlist_by_name(state: &DbState, title: String) -> Result<Playlist, DbErrors> {
+  let conn = &mut state.read()?;
+  playlist::table
+    .filter(playlist::title.eq(&title))
+    .first(conn)
+    .or_not_found("playlist", title)
+}
 
-// fuind_playlist_game
+pub fn find_playlists(state: &DbState, filter: PlaylistFilter) -> Result<Vec<Playlist>, DbErrors> {
+  let conn = &mut state.read()?;
+  let mut query = playlist::table.into_boxed();
+  if let Some(library) = filter.library {
+    query = query.filter(playlist::library.eq(library));
+  }
+  if let Some(extreme) = filter.extreme {
+    query = query.filter(playlist::extreme.eq(extreme));
+  }
+  Ok(query.order(playlist::title.asc()).load::<Playlist>(conn)?)
+}
 
-// remove_playlist_game
+pub fn create_playlist(
+  state: &DbState,
+  new_playlist: InsertablePlaylist,
+) -> Result<Playlist, DbErrors> {
+  let conn = &mut *state.write()?;
+  let p = Playlist {
+    id: uuid::Uuid::new_v4().to_string(),
+    title: new_playlist.title,
+    description: new_playlist.description,
+    author: new_playlist.author,
+    icon: new_playlist.icon,
+    library: new_playlist.library,
+    extreme: new_playlist.extreme,
+  };
+  diesel::insert_into(playlist::table)
+    .values(&p)
+    .execute(conn)?;
+  Ok(p)
+}
 
-// add_playlist_game
+/// Overwrites every field of an existing playlist. Its games are left untouched.
+pub fn update_playlist(state: &DbState, p: Playlist) -> Result<Playlist, DbErrors> {
+  let conn = &mut *state.write()?;
+  let updated = diesel::update(playlist::table.find(&p.id))
+    .set(&p)
+    .execute(conn)?;
+  if updated == 0 {
+    return Err(DbErrors::NotFound {
+      entity: "playlist",
+      key: p.id,
+    });
+  }
+  Ok(p)
+}
 
-// update_playlist_game
+/// Inserts or overwrites a playlist and replaces all of its entries with `games`, given as
+/// (game id, notes) pairs in playlist order.
+pub fn replace_playlist(
+  state: &DbState,
+  p: Playlist,
+  games: Vec<(String, String)>,
+) -> Result<(), DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    diesel::replace_into(playlist::table)
+      .values(&p)
+      .execute(conn)?;
+    diesel::delete(playlist_game::table.filter(playlist_game::playlistId.eq(&p.id)))
+      .execute(conn)?;
+    let rows: Vec<_> = games
+      .iter()
+      .enumerate()
+      .map(|(i, (game_id, notes))| {
+        (
+          playlist_game::playlistId.eq(&p.id),
+          playlist_game::order.eq(i as i32),
+          playlist_game::notes.eq(notes),
+          playlist_game::gameId.eq(game_id),
+        )
+      })
+      .collect();
+    for chunk in rows.chunks(200) {
+      diesel::insert_into(playlist_game::table)
+        .values(chunk)
+        .execute(conn)?;
+    }
+    Ok(())
+  })
+}
 
-// update_playlist_games
+/// Deletes a playlist along with all of its entries.
+pub fn remove_playlist(state: &DbState, playlist_id: String) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    diesel::delete(playlist_game::table.filter(playlist_game::playlistId.eq(&playlist_id)))
+      .execute(conn)?;
+    Ok(diesel::delete(playlist::table.find(&playlist_id)).execute(conn)?)
+  })
+}
+
+/// Loads the entries of a playlist in playlist order.
+pub fn find_playlist_games(
+  state: &DbState,
+  playlist_id: String,
+) -> Result<Vec<PlaylistGame>, DbErrors> {
+  let conn = &mut state.read()?;
+  Ok(load_playlist_games(conn, &playlist_id)?)
+}
+
+pub fn find_playlist_game(
+  state: &DbState,
+  playlist_id: String,
+  game_id: String,
+) -> Result<PlaylistGame, DbErrors> {
+  let conn = &mut state.read()?;
+  playlist_game::table
+    .filter(playlist_game::playlistId.eq(&playlist_id))
+    .filter(playlist_game::gameId.eq(&game_id))
+    .first(conn)
+    .or_not_found(
+      "playlist game",
+      format!("{} in playlist {}", game_id, playlist_id),
+    )
+}
+
+/// Appends a game to the end of a playlist.
+pub fn add_playlist_game(
+  state: &DbState,
+  playlist_id: String,
+  game_id: String,
+  notes: Option<String>,
+) -> Result<PlaylistGame, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    playlist::table
+      .find(&playlist_id)
+      .first::<Playlist>(conn)
+      .or_not_found("playlist", &playlist_id)?;
+    // SQLite doesn't enforce the foreign key, so check the game exists ourselves.
+    game::table
+      .find(&game_id)
+      .select(game::id)
+      .first::<String>(conn)
+      .or_not_found("game", &game_id)?;
+    let entries = load_playlist_games(conn, &playlist_id)?;
+    if entries
+      .iter()
+      .any(|e| e.game_id.as_deref() == Some(game_id.as_str()))
+    {
+      return Err(DbErrors::ConstraintViolation(format!(
+        "game {} is already in playlist {}",
+        game_id, playlist_id
+      )));
+    }
+    diesel::insert_into(playlist_game::table)
+      .values((
+        playlist_game::playlistId.eq(&playlist_id),
+        playlist_game::order.eq(entries.len() as i32),
+        playlist_game::notes.eq(notes.unwrap_or_default()),
+        playlist_game::gameId.eq(&game_id),
+      ))
+      .execute(conn)?;
+    let entry_id = diesel::select(last_insert_rowid()).get_result::<i32>(conn)?;
+    Ok(playlist_game::table.find(entry_id).first(conn)?)
+  })
+}
+
+/// Removes a game from a playlist and closes the gap it leaves in the ordering.
+pub fn remove_playlist_game(
+  state: &DbState,
+  playlist_id: String,
+  game_id: String,
+) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let removed = diesel::delete(
+      playlist_game::table
+        .filter(playlist_game::playlistId.eq(&playlist_id))
+        .filter(playlist_game::gameId.eq(&game_id)),
+    )
+    .execute(conn)?;
+    let entries = load_playlist_games(conn, &playlist_id)?;
+    write_order(conn, entries.iter().map(|e| e.id))?;
+    Ok(removed)
+  })
+}
+
+/// Edits the notes of a single playlist entry.
+pub fn update_playlist_game(
+  state: &DbState,
+  entry_id: i32,
+  notes: String,
+) -> Result<PlaylistGame, DbErrors> {
+  let conn = &mut *state.write()?;
+  let updated = diesel::update(playlist_game::table.find(entry_id))
+    .set(playlist_game::notes.eq(notes))
+    .execute(conn)?;
+  if updated == 0 {
+    return Err(DbErrors::NotFound {
+      entity: "playlist game",
+      key: entry_id.to_string(),
+    });
+  }
+  Ok(playlist_game::table.find(entry_id).first(conn)?)
+}
+
+/// Moves a game to `index` within its playlist, shifting the other entries around it.
+pub fn move_playlist_game(
+  state: &DbState,
+  playlist_id: String,
+  game_id: String,
+  index: usize,
+) -> Result<Vec<PlaylistGame>, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let mut entries = load_playlist_games(conn, &playlist_id)?;
+    let from = entries
+      .iter()
+      .position(|e| e.game_id.as_deref() == Some(game_id.as_str()))
+      .ok_or_else(|| DbErrors::NotFound {
+        entity: "playlist game",
+        key: format!("{} in playlist {}", game_id, playlist_id),
+      })?;
+    let entry = entries.remove(from);
+    entries.insert(index.min(entries.len()), entry);
+    write_order(conn, entries.iter().map(|e| e.id))?;
+    Ok(load_playlist_games(conn, &playlist_id)?)
+  })
+}
+
+/// Reorders a whole playlist. `game_ids` must contain exactly the games already in the playlist.
+pub fn update_playlist_games(
+  state: &DbState,
+  playlist_id: String,
+  game_ids: Vec<String>,
+) -> Result<Vec<PlaylistGame>, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let entries = load_playlist_games(conn, &playlist_id)?;
+    let mut ordered = Vec::with_capacity(entries.len());
+    for game_id in &game_ids {
+      match entries
+        .iter()
+        .find(|e| e.game_id.as_deref() == Some(game_id.as_str()))
+      {
+        Some(e) if !ordered.contains(&e.id) => ordered.push(e.id),
+        _ => {
+          return Err(DbErrors::ConstraintViolation(format!(
+            "game {} is missing from or duplicated in playlist {}",
+            game_id, playlist_id
+          )))
+        }
+      }
+    }
+    if ordered.len() != entries.len() {
+      return Err(DbErrors::ConstraintViolation(format!(
+        "new order for playlist {} is missing {} game(s)",
+        playlist_id,
+        entries.len() - ordered.len()
+      )));
+    }
+    write_order(conn, ordered.into_iter())?;
+    Ok(load_playlist_games(conn, &playlist_id)?)
+  })
+}
+
+fn load_playlist_games(
+  conn: &mut SqliteConnection,
+  playlist_id: &str,
+) -> Result<Vec<PlaylistGame>, diesel::result::Error> {
+  playlist_game::table
+    .filter(playlist_game::playlistId.eq(playlist_id))
+    .order((playlist_game::order.asc(), playlist_game::id.asc()))
+    .load::<PlaylistGame>(conn)
+}
+
+/// Rewrites the `order` column so the given entries are numbered 0..n in iteration order.
+fn write_order(
+  conn: &mut SqliteConnection,
+  entry_ids: impl Iterator<Item = i32>,
+) -> Result<(), diesel::result::Error> {
+  for (i, entry_id) in entry_ids.enumerate() {
+    diesel::update(playlist_game::table.find(entry_id))
+      .set(playlist_game::order.eq(i as i32))
+      .execute(conn)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::save_game;
+  use crate::{test_game, test_state};
+
+  fn new_playlist(state: &DbState) -> Playlist {
+    create_playlist(
+      state,
+      InsertablePlaylist {
+        title: "Favorites".to_string(),
+        description: String::new(),
+        author: String::new(),
+        icon: None,
+        library: "arcade".to_string(),
+        extreme: false,
+      },
+    )
+    .unwrap()
+  }
+
+  fn orders(entries: &[PlaylistGame]) -> Vec<(String, i32)> {
+    entries
+      .iter()
+      .map(|e| (e.game_id.clone().unwrap(), e.order))
+      .collect()
+  }
+
+  #[test]
+  fn playlist_order_stays_contiguous() {
+    let state = test_state();
+    for id in ["a", "b", "c", "d"] {
+      save_game(&state, test_game(id, id)).unwrap();
+    }
+    let p = new_playlist(&state);
+    for id in ["a", "b", "c", "d"] {
+      add_playlist_game(&state, p.id.clone(), id.to_string(), None).unwrap();
+    }
+    assert!(add_playlist_game(&state, p.id.clone(), "a".to_string(), None).is_err());
+
+    remove_playlist_game(&state, p.id.clone(), "b".to_string()).unwrap();
+    let entries = move_playlist_game(&state, p.id.clone(), "d".to_string(), 0).unwrap();
+    assert_eq!(
+      orders(&entries),
+      vec![
+        ("d".to_string(), 0),
+        ("a".to_string(), 1),
+        ("c".to_string(), 2)
+      ]
+    );
+
+    assert!(update_playlist_games(&state, p.id.clone(), vec!["a".to_string()]).is_err());
+    let entries = update_playlist_games(
+      &state,
+      p.id.clone(),
+      vec!["c".to_string(), "a".to_string(), "d".to_string()],
+    )
+    .unwrap();
+    assert_eq!(
+      orders(&entries),
+      vec![
+        ("c".to_string(), 0),
+        ("a".to_string(), 1),
+        ("d".to_string(), 2)
+      ]
+    );
+
+    let filter = PlaylistFilter {
+      library: Some("theatre".to_string()),
+      extreme: None,
+    };
+    assert!(find_playlists(&state, filter).unwrap().is_empty());
+    remove_playlist(&state, p.id.clone()).unwrap();
+    assert!(find_playlist_games(&state, p.id).unwrap().is_empty());
+  }
+
+  #[test]
+  fn unknown_games_are_not_added() {
+    let state = test_state();
+    let p = new_playlist(&state);
+    assert!(matches!(
+      add_playlist_game(&state, p.id.clone(), "missing".to_string(), None),
+      Err(DbErrors::NotFound { .. })
+    ));
+    assert!(find_playlist_games(&state, p.id).unwrap().is_empty());
+  }
+}
@@ -0,0 +1,346 @@
+//! Parses the launcher's search syntax into a `ParsedSearch`.
+//!
+//! Supported terms, any of which can be negated with a leading `-`:
+//! * `word` or `"a phrase"` - matched against title, alternate titles, developer and publisher.
+//! * `field:value` or `field:"a value"` - matched against a single game column.
+//! * `tag:name` or `tag:"a name"` - matched against every alias of the game's tags.
+//! * `#tag` or `#"a tag"` - shorthand for `tag:tag`.
+
+use crate::models::GameRelation;
+use crate::types::ParsedSearch;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum SearchParseErrorKind {
+  UnknownField { field: String },
+  InvalidValue { field: String, value: String },
+  EmptyValue { field: String },
+  UnterminatedQuote,
+}
+
+/// A problem with a single term of the search. `start` and `end` are character offsets into the
+/// search string, covering the whole offending term.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchParseError {
+  #[serde(flatten)]
+  pub kind: SearchParseErrorKind,
+  pub start: usize,
+  pub end: usize,
+}
+
+impl std::fmt::Display for SearchParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.kind {
+      SearchParseErrorKind::UnknownField { field } => {
+        write!(f, "unknown search field \"{}\"", field)?;
+      }
+      SearchParseErrorKind::InvalidValue { field, value } => {
+        write!(
+          f,
+          "invalid value \"{}\" for search field \"{}\"",
+          value, field
+        )?;
+      }
+      SearchParseErrorKind::EmptyValue { field } => {
+        write!(f, "missing value for search field \"{}\"", field)?;
+      }
+      SearchParseErrorKind::UnterminatedQuote => {
+        write!(f, "unterminated quote")?;
+      }
+    }
+    write!(f, " at {}..{}", self.start, self.end)
+  }
+}
+
+impl std::error::Error for SearchParseError {}
+
+/// Parses a search string. Every bad term is reported, not just the first one.
+pub fn parse_search(input: &str) -> Result<ParsedSearch, Vec<SearchParseError>> {
+  let mut search = ParsedSearch::default();
+  let mut errors = vec![];
+  let mut lexer = Lexer {
+    chars: input.chars().collect(),
+    pos: 0,
+  };
+
+  while let Some(term) = lexer.next_term() {
+    let term = match term {
+      Ok(t) => t,
+      Err(e) => {
+        errors.push(e);
+        continue;
+      }
+    };
+    let whitelist = !term.negated;
+    match term.field {
+      // Nothing to match on, e.g. `""`.
+      None if term.value.is_empty() => {}
+      None => {
+        if whitelist {
+          search.generic_whitelist.push(term.value);
+        } else {
+          search.generic_blacklist.push(term.value);
+        }
+      }
+      Some(field) if is_tag_field(&field) => {
+        if term.value.is_empty() {
+          errors.push(SearchParseError {
+            kind: SearchParseErrorKind::EmptyValue { field },
+            start: term.start,
+            end: term.end,
+          });
+        } else if whitelist {
+          search.tag_whitelist.push(term.value);
+        } else {
+          search.tag_blacklist.push(term.value);
+        }
+      }
+      Some(field) => match field_relation(&field, &term.value) {
+        Ok(relation) => {
+          if whitelist {
+            search.whitelist.push(relation);
+          } else {
+            search.blacklist.push(relation);
+          }
+        }
+        Err(kind) => errors.push(SearchParseError {
+          kind,
+          start: term.start,
+          end: term.end,
+        }),
+      },
+    }
+  }
+
+  if errors.is_empty() {
+    Ok(search)
+  } else {
+    Err(errors)
+  }
+}
+
+fn is_tag_field(field: &str) -> bool {
+  matches!(field.to_lowercase().as_str(), "tag" | "tags")
+}
+
+/// Maps a search field name, including the legacy launcher's shorthands, onto a game column.
+fn field_relation(field: &str, value: &str) -> Result<GameRelation, SearchParseErrorKind> {
+  if value.is_empty() {
+    return Err(SearchParseErrorKind::EmptyValue {
+      field: field.to_string(),
+    });
+  }
+  let v = value.to_string();
+  let relation = match field.to_lowercase().as_str() {
+    "id" => GameRelation::id(v),
+    "title" => GameRelation::title(v),
+    "alt" | "alternatetitles" | "alternate_titles" => GameRelation::alternate_titles(v),
+    "series" => GameRelation::series(v),
+    "dev" | "developer" => GameRelation::developer(v),
+    "pub" | "publisher" => GameRelation::publisher(v),
+    "platform" => GameRelation::platform(v),
+    "mode" | "playmode" | "play_mode" => GameRelation::play_mode(v),
+    "status" => GameRelation::status(v),
+    "notes" => GameRelation::notes(v),
+    "src" | "source" => GameRelation::source(v),
+    "app" | "applicationpath" | "application_path" => GameRelation::application_path(v),
+    "cmd" | "launchcommand" | "launch_command" => GameRelation::launch_command(v),
+    "date" | "releasedate" | "release_date" => GameRelation::release_date(v),
+    "version" => GameRelation::version(v),
+    "desc" | "description" | "originaldescription" | "original_description" => {
+      GameRelation::original_description(v)
+    }
+    "lang" | "language" => GameRelation::language(v),
+    "library" => GameRelation::library(v),
+    "broken" => GameRelation::broken(parse_bool(field, value)?),
+    "extreme" => GameRelation::extreme(parse_bool(field, value)?),
+    _ => {
+      return Err(SearchParseErrorKind::UnknownField {
+        field: field.to_string(),
+      })
+    }
+  };
+  Ok(relation)
+}
+
+fn parse_bool(field: &str, value: &str) -> Result<bool, SearchParseErrorKind> {
+  match value.to_lowercase().as_str() {
+    "true" | "yes" | "1" => Ok(true),
+    "false" | "no" | "0" => Ok(false),
+    _ => Err(SearchParseErrorKind::InvalidValue {
+      field: field.to_string(),
+      value: value.to_string(),
+    }),
+  }
+}
+
+struct Term {
+  negated: bool,
+  field: Option<String>,
+  value: String,
+  start: usize,
+  end: usize,
+}
+
+struct Lexer {
+  chars: Vec<char>,
+  pos: usize,
+}
+
+impl Lexer {
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).copied()
+  }
+
+  fn next_term(&mut self) -> Option<Result<Term, SearchParseError>> {
+    while self.peek()?.is_whitespace() {
+      self.pos += 1;
+    }
+    let start = self.pos;
+
+    // A lone "-" is just a word.
+    let negated = self.peek() == Some('-')
+      && matches!(self.chars.get(self.pos + 1), Some(c) if !c.is_whitespace());
+    if negated {
+      self.pos += 1;
+    }
+
+    let field = if self.peek() == Some('#') {
+      self.pos += 1;
+      Some("tag".to_string())
+    } else if self.peek() == Some('"') {
+      None
+    } else {
+      // Look ahead for "field:" before any whitespace or quote.
+      let word_end = self.chars[self.pos..]
+        .iter()
+        .position(|c| c.is_whitespace() || *c == '"' || *c == ':')
+        .map(|i| self.pos + i)
+        .unwrap_or(self.chars.len());
+      if word_end > self.pos && self.chars.get(word_end) == Some(&':') {
+        let field: String = self.chars[self.pos..word_end].iter().collect();
+        self.pos = word_end + 1;
+        Some(field)
+      } else {
+        None
+      }
+    };
+
+    let value = match self.read_value() {
+      Some(v) => v,
+      None => {
+        return Some(Err(SearchParseError {
+          kind: SearchParseErrorKind::UnterminatedQuote,
+          start,
+          end: self.pos,
+        }))
+      }
+    };
+
+    Some(Ok(Term {
+      negated,
+      field,
+      value,
+      start,
+      end: self.pos,
+    }))
+  }
+
+  /// Reads a quoted phrase or a bare word. Returns None if a quote is never closed.
+  fn read_value(&mut self) -> Option<String> {
+    let mut value = String::new();
+    if self.peek() == Some('"') {
+      self.pos += 1;
+      loop {
+        match self.peek() {
+          None => return None,
+          Some('"') => {
+            self.pos += 1;
+            break;
+          }
+          Some('\\') if self.chars.get(self.pos + 1) == Some(&'"') => {
+            value.push('"');
+            self.pos += 2;
+          }
+          Some(c) => {
+            value.push(c);
+            self.pos += 1;
+          }
+        }
+      }
+    } else {
+      while let Some(c) = self.peek() {
+        if c.is_whitespace() {
+          break;
+        }
+        value.push(c);
+        self.pos += 1;
+      }
+    }
+    Some(value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_terms() {
+    let search =
+      parse_search(r#"sonic title:foo -dev:"Some Dev" "two words" #Puzzle tag:"Point and Click""#)
+        .unwrap();
+    assert_eq!(search.generic_whitelist, vec!["sonic", "two words"]);
+    assert!(search.generic_blacklist.is_empty());
+    assert_eq!(
+      search.whitelist,
+      vec![GameRelation::title("foo".to_string())]
+    );
+    assert_eq!(search.tag_whitelist, vec!["Puzzle", "Point and Click"]);
+    assert_eq!(
+      search.blacklist,
+      vec![GameRelation::developer("Some Dev".to_string())]
+    );
+  }
+
+  #[test]
+  fn negated_words_and_booleans() {
+    let search = parse_search("-cat - extreme:no -#Gore").unwrap();
+    assert_eq!(search.generic_blacklist, vec!["cat"]);
+    assert_eq!(search.tag_blacklist, vec!["Gore"]);
+    assert_eq!(search.generic_whitelist, vec!["-"]);
+    assert_eq!(search.whitelist, vec![GameRelation::extreme(false)]);
+  }
+
+  #[test]
+  fn errors_have_positions() {
+    let errors = parse_search(r#"ok colour:red broken:maybe "open"#).unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        SearchParseError {
+          kind: SearchParseErrorKind::UnknownField {
+            field: "colour".to_string()
+          },
+          start: 3,
+          end: 13,
+        },
+        SearchParseError {
+          kind: SearchParseErrorKind::InvalidValue {
+            field: "broken".to_string(),
+            value: "maybe".to_string()
+          },
+          start: 14,
+          end: 26,
+        },
+        SearchParseError {
+          kind: SearchParseErrorKind::UnterminatedQuote,
+          start: 27,
+          end: 32,
+        },
+      ]
+    );
+  }
+}
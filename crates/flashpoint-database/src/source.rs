@@ -0,0 +1,248 @@
+use crate::last_insert_rowid;
+use crate::models::{Source, SourceData};
+use crate::schema::{source, source_data};
+use crate::types::{DbErrors, DbState};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertableSource {
+  pub name: String,
+  /// Where the source's index was read from. Identifies the source when it's registered again.
+  pub source_file_url: String,
+  /// Prepended to every entry's `urlPath`, e.g. `https://example.com/` or `file:///mnt/mirror/`.
+  pub base_url: String,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterSourceSummary {
+  pub source_id: i32,
+  pub added: usize,
+  pub updated: usize,
+  pub removed: usize,
+}
+
+/// A URL a data pack can be fetched from.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceUrl {
+  pub source_id: i32,
+  pub source_name: String,
+  pub url: String,
+}
+
+pub fn find_sources(state: &DbState) -> Result<Vec<Source>, DbErrors> {
+  let conn = &mut state.read()?;
+  Ok(source::table.order(source::name.asc()).load(conn)?)
+}
+
+/// Registers a source along with its index of (sha256, url path) entries. Registering the same
+/// `source_file_url` again updates that source and replaces its entries.
+pub fn register_source(
+  state: &DbState,
+  new_source: InsertableSource,
+  entries: Vec<(String, String)>,
+) -> Result<RegisterSourceSummary, DbErrors> {
+  let conn = &mut *state.write()?;
+  // Later entries for the same hash win.
+  let entries: HashMap<String, String> = entries
+    .into_iter()
+    .map(|(sha256, url_path)| (sha256.to_lowercase(), url_path))
+    .collect();
+  let now = chrono::Utc::now().naive_utc();
+
+  conn.transaction(|conn| {
+    let existing = source::table
+      .filter(source::sourceFileUrl.eq(&new_source.source_file_url))
+      .first::<Source>(conn)
+      .optional()?;
+    let source_id = match existing {
+      Some(s) => {
+        diesel::update(source::table.find(s.id))
+          .set((
+            source::name.eq(&new_source.name),
+            source::baseUrl.eq(&new_source.base_url),
+            source::lastUpdated.eq(now),
+            source::count.eq(entries.len() as i32),
+          ))
+          .execute(conn)?;
+        s.id
+      }
+      None => {
+        diesel::insert_into(source::table)
+          .values((
+            source::name.eq(&new_source.name),
+            source::dateAdded.eq(now),
+            source::lastUpdated.eq(now),
+            source::sourceFileUrl.eq(&new_source.source_file_url),
+            source::baseUrl.eq(&new_source.base_url),
+            source::count.eq(entries.len() as i32),
+          ))
+          .execute(conn)?;
+        diesel::select(last_insert_rowid()).get_result::<i32>(conn)?
+      }
+    };
+
+    let mut summary = RegisterSourceSummary {
+      source_id,
+      ..Default::default()
+    };
+    let mut current: HashMap<String, SourceData> = source_data::table
+      .filter(source_data::sourceId.eq(source_id))
+      .load::<SourceData>(conn)?
+      .into_iter()
+      .map(|d| (d.sha256.clone(), d))
+      .collect();
+
+    let mut inserts = vec![];
+    for (sha256, url_path) in &entries {
+      match current.remove(sha256) {
+        Some(d) if &d.url_path == url_path => {}
+        Some(d) => {
+          diesel::update(source_data::table.find(d.id))
+            .set(source_data::urlPath.eq(url_path))
+            .execute(conn)?;
+          summary.updated += 1;
+        }
+        None => inserts.push((
+          source_data::sourceId.eq(source_id),
+          source_data::sha256.eq(sha256),
+          source_data::urlPath.eq(url_path),
+        )),
+      }
+    }
+    for chunk in inserts.chunks(300) {
+      summary.added += diesel::insert_into(source_data::table)
+        .values(chunk)
+        .execute(conn)?;
+    }
+    // Whatever is left is no longer in the index.
+    let stale: Vec<i32> = current.values().map(|d| d.id).collect();
+    for chunk in stale.chunks(500) {
+      summary.removed +=
+        diesel::delete(source_data::table.filter(source_data::id.eq_any(chunk))).execute(conn)?;
+    }
+    Ok(summary)
+  })
+}
+
+/// Deletes a source along with its entries.
+pub fn remove_source(state: &DbState, source_id: i32) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    diesel::delete(source_data::table.filter(source_data::sourceId.eq(source_id))).execute(conn)?;
+    Ok(diesel::delete(source::table.find(source_id)).execute(conn)?)
+  })
+}
+
+/// Lists every URL that a data pack with the given sha256 can be fetched from.
+pub fn find_source_urls(state: &DbState, sha256: String) -> Result<Vec<SourceUrl>, DbErrors> {
+  let conn = &mut state.read()?;
+  let rows = source_data::table
+    .inner_join(source::table)
+    .filter(source_data::sha256.eq(sha256.to_lowercase()))
+    .order((source::name.asc(), source::id.asc()))
+    .select((
+      source::id,
+      source::name,
+      source::baseUrl,
+      source_data::urlPath,
+    ))
+    .load::<(i32, String, String, String)>(conn)?;
+  Ok(
+    rows
+      .into_iter()
+      .map(|(source_id, source_name, base_url, url_path)| SourceUrl {
+        source_id,
+        source_name,
+        url: join_url(&base_url, &url_path),
+      })
+      .collect(),
+  )
+}
+
+fn join_url(base_url: &str, url_path: &str) -> String {
+  format!(
+    "{}/{}",
+    base_url.trim_end_matches('/'),
+    url_path.trim_start_matches('/')
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_state;
+
+  fn entries(list: &[(&str, &str)]) -> Vec<(String, String)> {
+    list
+      .iter()
+      .map(|(h, p)| (h.to_string(), p.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn register_and_resolve_sources() {
+    let state = test_state();
+    let mirror = || InsertableSource {
+      name: "LAN mirror".to_string(),
+      source_file_url: "/mnt/mirror/index.txt".to_string(),
+      base_url: "file:///mnt/mirror/".to_string(),
+    };
+    let first = register_source(
+      &state,
+      mirror(),
+      entries(&[("AA", "/games/a.zip"), ("bb", "games/b.zip")]),
+    )
+    .unwrap();
+    assert_eq!(first.added, 2);
+    register_source(
+      &state,
+      InsertableSource {
+        name: "Web".to_string(),
+        source_file_url: "https://example.com/index.txt".to_string(),
+        base_url: "https://example.com".to_string(),
+      },
+      entries(&[("aa", "a.zip")]),
+    )
+    .unwrap();
+
+    assert_eq!(
+      find_source_urls(&state, "aa".to_string())
+        .unwrap()
+        .into_iter()
+        .map(|u| u.url)
+        .collect::<Vec<_>>(),
+      vec![
+        "file:///mnt/mirror/games/a.zip",
+        "https://example.com/a.zip"
+      ]
+    );
+
+    let again = register_source(
+      &state,
+      mirror(),
+      entries(&[("aa", "games/a2.zip"), ("cc", "games/c.zip")]),
+    )
+    .unwrap();
+    assert_eq!(
+      again,
+      RegisterSourceSummary {
+        source_id: first.source_id,
+        added: 1,
+        updated: 1,
+        removed: 1,
+      }
+    );
+    assert!(find_source_urls(&state, "bb".to_string())
+      .unwrap()
+      .is_empty());
+    assert_eq!(find_sources(&state).unwrap()[0].count, 2);
+
+    remove_source(&state, first.source_id).unwrap();
+    assert_eq!(find_source_urls(&state, "cc".to_string()).unwrap().len(), 0);
+  }
+}
@@ -1,8 +1,14 @@
+use crate::filter::get_excluded_tag_ids_query;
+use crate::game::rebuild_tags_str;
+use crate::last_insert_rowid;
 use crate::models::{Tag, TagAlias, TagCategory};
-use crate::schema::{tag, tag_alias, tag_category};
-use crate::types::DbState;
+use crate::schema::{game_tags_tag, tag, tag_alias, tag_category};
+use crate::types::{DbErrors, DbState, OrNotFound};
 use diesel::prelude::*;
-use serde::Deserialize;
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Deserialize, Insertable, AsChangeset)]
 #[diesel(table_name = tag_category)]
@@ -12,108 +18,745 @@ pub struct InsertableTagCategory {
   pub description: Option<String>,
 }
 
-// find_tags
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertableTag {
+  /// The name of the primary alias to create alongside the tag.
+  pub name: String,
+  pub category_id: Option<i32>,
+  pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize, AsChangeset)]
+#[diesel(table_name = tag, treat_none_as_null = true)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveableTag {
+  #[diesel(column_name = categoryId)]
+  pub category_id: Option<i32>,
+  pub description: Option<String>,
+}
+
+/// Loads every tag along with all of its aliases.
+pub fn find_tags(state: &DbState) -> Result<Vec<(Tag, Vec<TagAlias>)>, DbErrors> {
+  let conn = &mut state.read()?;
+  let tags = tag::table.load::<Tag>(conn)?;
+  let mut aliases: HashMap<i32, Vec<TagAlias>> = HashMap::new();
+  for alias in tag_alias::table.load::<TagAlias>(conn)? {
+    if let Some(tag_id) = alias.tag_id {
+      aliases.entry(tag_id).or_default().push(alias);
+    }
+  }
+  Ok(
+    tags
+      .into_iter()
+      .map(|t| {
+        let a = aliases.remove(&t.id).unwrap_or_default();
+        (t, a)
+      })
+      .collect(),
+  )
+}
+
+/// Creates a tag and its primary alias in a single transaction.
+pub fn create_tag(
+  state: &DbState,
+  new_tag: InsertableTag,
+) -> Result<(Tag, Vec<TagAlias>), DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    diesel::insert_into(tag::table)
+      .values((
+        tag::dateModified.eq(chrono::Utc::now().naive_utc()),
+        tag::categoryId.eq(new_tag.category_id),
+        tag::description.eq(&new_tag.description),
+      ))
+      .execute(conn)?;
+    let tag_id = diesel::select(last_insert_rowid()).get_result::<i32>(conn)?;
+
+    diesel::insert_into(tag_alias::table)
+      .values((
+        tag_alias::tagId.eq(tag_id),
+        tag_alias::name.eq(&new_tag.name),
+      ))
+      .execute(conn)?;
+    let alias_id = diesel::select(last_insert_rowid()).get_result::<i32>(conn)?;
+
+    diesel::update(tag::table.find(tag_id))
+      .set(tag::primaryAliasId.eq(alias_id))
+      .execute(conn)?;
+    load_tag(conn, tag_id)
+  })
+}
+
+/// Deletes a tag, its aliases, and removes it from every game it was attached to.
+pub fn delete_tag(state: &DbState, tag_id: i32) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let game_ids = game_tags_tag::table
+      .filter(game_tags_tag::tagId.eq(tag_id))
+      .select(game_tags_tag::gameId)
+      .load::<String>(conn)?;
+    diesel::delete(game_tags_tag::table.filter(game_tags_tag::tagId.eq(tag_id))).execute(conn)?;
+    diesel::delete(tag_alias::table.filter(tag_alias::tagId.eq(tag_id))).execute(conn)?;
+    let deleted = diesel::delete(tag::table.find(tag_id)).execute(conn)?;
+    rebuild_tags_str(conn, &game_ids)?;
+    Ok(deleted)
+  })
+}
+
+pub fn save_tag(state: &DbState, tag_id: i32, changes: SaveableTag) -> Result<Tag, DbErrors> {
+  let conn = &mut *state.write()?;
+  diesel::update(tag::table.find(tag_id))
+    .set((
+      &changes,
+      tag::dateModified.eq(chrono::Utc::now().naive_utc()),
+    ))
+    .execute(conn)?;
+  tag::table
+    .find(tag_id)
+    .first(conn)
+    .or_not_found("tag", tag_id)
+}
 
-// create_tag
+/// Renames an alias. If it is the primary alias of its tag, the `tagsStr` of every game using
+/// that tag is updated to match.
+pub fn save_tag_alias(state: &DbState, alias_id: i32, name: String) -> Result<TagAlias, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    diesel::update(tag_alias::table.find(alias_id))
+      .set(tag_alias::name.eq(name))
+      .execute(conn)?;
+    let alias = tag_alias::table
+      .find(alias_id)
+      .first::<TagAlias>(conn)
+      .or_not_found("tag alias", alias_id)?;
+    if let Some(tag_id) = alias.tag_id {
+      touch_tag(conn, tag_id)?;
+    }
+    Ok(alias)
+  })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeTagsSummary {
+  pub games_affected: usize,
+  pub aliases_moved: usize,
+  pub aliases_deleted: usize,
+}
+
+/// Merges the source tag into the target tag. Every game tagged with the source ends up tagged with
+/// the target, the source's aliases are either moved onto the target or deleted, and the source tag
+/// is removed. Runs in a single transaction.
+pub fn merge_tags(
+  state: &DbState,
+  source_id: i32,
+  target_id: i32,
+  move_aliases: bool,
+) -> Result<MergeTagsSummary, DbErrors> {
+  let conn = &mut *state.write()?;
+  if source_id == target_id {
+    return Err(DbErrors::ConstraintViolation(format!(
+      "cannot merge tag {} into itself",
+      source_id
+    )));
+  }
+  conn.transaction(|conn| {
+    // Make sure both tags exist before touching anything.
+    tag::table
+      .find(source_id)
+      .first::<Tag>(conn)
+      .or_not_found("tag", source_id)?;
+    tag::table
+      .find(target_id)
+      .first::<Tag>(conn)
+      .or_not_found("tag", target_id)?;
 
-// delete_tag
+    let game_ids = game_tags_tag::table
+      .filter(game_tags_tag::tagId.eq(source_id))
+      .select(game_tags_tag::gameId)
+      .load::<String>(conn)?;
+    // Games already tagged with the target would produce duplicate primary keys, so ignore those.
+    for chunk in game_ids.chunks(500) {
+      let rows: Vec<_> = chunk
+        .iter()
+        .map(|id| {
+          (
+            game_tags_tag::gameId.eq(id),
+            game_tags_tag::tagId.eq(target_id),
+          )
+        })
+        .collect();
+      diesel::insert_or_ignore_into(game_tags_tag::table)
+        .values(&rows)
+        .execute(conn)?;
+    }
+    diesel::delete(game_tags_tag::table.filter(game_tags_tag::tagId.eq(source_id)))
+      .execute(conn)?;
 
-// save_tag
+    let source_aliases = tag_alias::table.filter(tag_alias::tagId.eq(source_id));
+    let (aliases_moved, aliases_deleted) = if move_aliases {
+      let moved = diesel::update(source_aliases)
+        .set(tag_alias::tagId.eq(target_id))
+        .execute(conn)?;
+      (moved, 0)
+    } else {
+      // The primary alias is referenced by the tag row, detach it before deleting.
+      diesel::update(tag::table.find(source_id))
+        .set(tag::primaryAliasId.eq(None::<i32>))
+        .execute(conn)?;
+      (0, diesel::delete(source_aliases).execute(conn)?)
+    };
 
-// save_tag_alias
+    diesel::delete(tag::table.find(source_id)).execute(conn)?;
+    diesel::update(tag::table.find(target_id))
+      .set(tag::dateModified.eq(chrono::Utc::now().naive_utc()))
+      .execute(conn)?;
+    rebuild_tags_str(conn, &game_ids)?;
 
-// merge_tags
+    Ok(MergeTagsSummary {
+      games_affected: game_ids.len(),
+      aliases_moved,
+      aliases_deleted,
+    })
+  })
+}
 
 // cleanup_tag_aliases (still needed?)
 
-// find_tag_suggestions
+/// A tag whose aliases matched a partial tag name.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TagSuggestion {
+  pub tag_id: i32,
+  pub primary_alias: String,
+  /// The alias that matched best, which may differ from the primary alias.
+  pub matched_alias: String,
+  pub category: Option<String>,
+  pub color: Option<String>,
+  /// The number of games with this tag.
+  pub games_count: i64,
+}
+
+/// Suggests up to `limit` tags with an alias containing `partial`, ignoring case. Exact matches
+/// come first, then prefix matches, then the rest, with more used tags first within each group.
+/// Tags hidden by the active tag filter groups are never suggested.
+pub fn find_tag_suggestions(
+  state: &DbState,
+  partial: String,
+  limit: usize,
+) -> Result<Vec<TagSuggestion>, DbErrors> {
+  let conn = &mut state.read()?;
+  let needle = partial.to_lowercase();
+  if needle.is_empty() {
+    return Ok(vec![]);
+  }
+  let pattern = format!(
+    "%{}%",
+    needle
+      .replace('\\', "\\\\")
+      .replace('%', "\\%")
+      .replace('_', "\\_")
+  );
+  let mut query = tag_alias::table
+    .filter(tag_alias::name.like(pattern).escape('\\'))
+    .into_boxed();
+  let exclusions = &state.exclusions();
+  if !exclusions.tags.is_empty() || !exclusions.categories.is_empty() {
+    query = query.filter(
+      tag_alias::tagId
+        .assume_not_null()
+        .ne_all(get_excluded_tag_ids_query(exclusions)),
+    );
+  }
+  let aliases = query.load::<TagAlias>(conn)?;
+
+  // Tag ID -> (rank, best matching alias). Lower ranks are better.
+  let mut best: HashMap<i32, (u8, String)> = HashMap::new();
+  for alias in aliases {
+    let tag_id = match alias.tag_id {
+      Some(id) => id,
+      None => continue,
+    };
+    let name = alias.name.to_lowercase();
+    let rank = if name == needle {
+      0
+    } else if name.starts_with(&needle) {
+      1
+    } else {
+      2
+    };
+    match best.get(&tag_id) {
+      Some((r, _)) if *r <= rank => {}
+      _ => {
+        best.insert(tag_id, (rank, alias.name));
+      }
+    }
+  }
+  let tag_ids: Vec<i32> = best.keys().copied().collect();
 
-pub fn find_tag_categories(state: &mut DbState) -> Vec<TagCategory> {
+  let mut counts: HashMap<i32, i64> = HashMap::new();
+  for chunk in tag_ids.chunks(500) {
+    let rows = game_tags_tag::table
+      .filter(game_tags_tag::tagId.eq_any(chunk))
+      .group_by(game_tags_tag::tagId)
+      .select((game_tags_tag::tagId, diesel::dsl::count_star()))
+      .load::<(i32, i64)>(conn)?;
+    counts.extend(rows);
+  }
+
+  let mut suggestions = vec![];
+  for chunk in tag_ids.chunks(500) {
+    let rows = tag::table
+      .inner_join(tag_alias::table.on(tag::primaryAliasId.eq(tag_alias::id.nullable())))
+      .left_join(tag_category::table)
+      .filter(tag::id.eq_any(chunk))
+      .select((
+        tag::id,
+        tag_alias::name,
+        tag_category::name.nullable(),
+        tag_category::color.nullable(),
+      ))
+      .load::<(i32, String, Option<String>, Option<String>)>(conn)?;
+    for (tag_id, primary_alias, category, color) in rows {
+      let (rank, matched_alias) = best.remove(&tag_id).unwrap_or_default();
+      suggestions.push((
+        rank,
+        TagSuggestion {
+          tag_id,
+          primary_alias,
+          matched_alias,
+          category,
+          color,
+          games_count: counts.get(&tag_id).copied().unwrap_or(0),
+        },
+      ));
+    }
+  }
+
+  suggestions.sort_by(|(rank_a, a), (rank_b, b)| {
+    rank_a
+      .cmp(rank_b)
+      .then(b.games_count.cmp(&a.games_count))
+      .then_with(|| a.primary_alias.cmp(&b.primary_alias))
+  });
+  Ok(
+    suggestions
+      .into_iter()
+      .take(limit)
+      .map(|(_, s)| s)
+      .collect(),
+  )
+}
+
+pub fn find_tag_categories(state: &DbState) -> Result<Vec<TagCategory>, DbErrors> {
+  let conn = &mut state.read()?;
   use crate::schema::tag_category::dsl::*;
-  tag_category
-    .load::<TagCategory>(&mut state.conn)
-    .expect("Error loading tag categories")
+  Ok(tag_category.load::<TagCategory>(conn)?)
 }
 
 pub fn create_tag_category(
-  state: &mut DbState,
+  state: &DbState,
   new_category: InsertableTagCategory,
-) -> Result<TagCategory, diesel::result::Error> {
+) -> Result<TagCategory, DbErrors> {
+  let conn = &mut *state.write()?;
   diesel::insert_into(tag_category::table)
     .values(&new_category)
-    .execute(&mut state.conn)?;
+    .execute(conn)?;
   // TODO: Broadcast changes?
   // Find and return the newly created category
   tag_category::table
-    .filter(tag_category::name.eq(new_category.name))
-    .first(&mut state.conn)
+    .filter(tag_category::name.eq(&new_category.name))
+    .first(conn)
+    .or_not_found("tag category", new_category.name)
 }
 
 pub fn save_tag_category(
-  state: &mut DbState,
+  state: &DbState,
+  category_id: i32,
   category: InsertableTagCategory,
-) -> Result<usize, diesel::result::Error> {
-  diesel::update(tag_category::table)
+) -> Result<TagCategory, DbErrors> {
+  let conn = &mut *state.write()?;
+  let updated = diesel::update(tag_category::table.find(category_id))
     .set(&category)
-    .execute(&mut state.conn)
+    .execute(conn)?;
+  if updated == 0 {
+    return Err(DbErrors::NotFound {
+      entity: "tag category",
+      key: category_id.to_string(),
+    });
+  }
+  Ok(tag_category::table.find(category_id).first(conn)?)
 }
 
-pub fn get_tag_category(
-  state: &mut DbState,
-  category_id: i32,
-) -> Result<TagCategory, diesel::result::Error> {
+pub fn get_tag_category(state: &DbState, category_id: i32) -> Result<TagCategory, DbErrors> {
+  let conn = &mut state.read()?;
   tag_category::table
     .filter(tag_category::id.eq(category_id))
-    .first(&mut state.conn)
+    .first(conn)
+    .or_not_found("tag category", category_id)
 }
 
-pub fn get_tag_category_by_name(
-  state: &mut DbState,
-  name: String,
-) -> Result<TagCategory, diesel::result::Error> {
+pub fn get_tag_category_by_name(state: &DbState, name: String) -> Result<TagCategory, DbErrors> {
+  let conn = &mut state.read()?;
   tag_category::table
-    .filter(tag_category::name.eq(name))
-    .first(&mut state.conn)
+    .filter(tag_category::name.eq(&name))
+    .first(conn)
+    .or_not_found("tag category", name)
 }
 
-pub fn delete_tag_category(
-  state: &mut DbState,
-  category_id: i32,
-) -> Result<usize, diesel::result::Error> {
-  diesel::delete(tag_category::table.filter(tag_category::id.eq(category_id)))
-    .execute(&mut state.conn)
+pub fn delete_tag_category(state: &DbState, category_id: i32) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  Ok(diesel::delete(tag_category::table.filter(tag_category::id.eq(category_id))).execute(conn)?)
 }
 
 // find_game_tags
 
-// get_tag_by_id
+pub fn get_tag_by_id(state: &DbState, tag_id: i32) -> Result<(Tag, Vec<TagAlias>), DbErrors> {
+  let conn = &mut state.read()?;
+  load_tag(conn, tag_id)
+}
 
 // get_tag_by_name
 
-pub fn find_tag_by_name(
-  state: &mut DbState,
-  name: String,
-) -> Result<(Tag, Vec<TagAlias>), diesel::result::Error> {
+pub fn find_tag_by_name(state: &DbState, name: String) -> Result<(Tag, Vec<TagAlias>), DbErrors> {
+  let conn = &mut state.read()?;
   // Load tag
   let alias = tag_alias::table
-    .filter(tag_alias::name.eq(name))
-    .first::<TagAlias>(&mut state.conn)?;
+    .filter(tag_alias::name.eq(&name))
+    .first::<TagAlias>(conn)
+    .or_not_found("tag alias", &name)?;
+  let tag_id = alias.tag_id.ok_or(DbErrors::BrokenReference {
+    entity: "tag alias",
+    key: name,
+    missing: "tag",
+  })?;
   let tag_obj = tag::table
-    .filter(tag::id.eq(alias.tag_id.unwrap()))
-    .first::<Tag>(&mut state.conn)?;
+    .filter(tag::id.eq(tag_id))
+    .first::<Tag>(conn)
+    .or_not_found("tag", tag_id)?;
 
   // Load aliases
   let aliases = tag_alias::table
     .filter(tag_alias::tagId.eq(tag_obj.id))
-    .load::<TagAlias>(&mut state.conn)?;
+    .load::<TagAlias>(conn)?;
 
   Ok((tag_obj, aliases))
 }
 
-// add_alias_to_tag
+pub fn add_alias_to_tag(state: &DbState, tag_id: i32, name: String) -> Result<TagAlias, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    // Make sure the tag exists before attaching anything to it.
+    tag::table
+      .find(tag_id)
+      .first::<Tag>(conn)
+      .or_not_found("tag", tag_id)?;
+    diesel::insert_into(tag_alias::table)
+      .values((tag_alias::tagId.eq(tag_id), tag_alias::name.eq(name)))
+      .execute(conn)?;
+    let alias_id = diesel::select(last_insert_rowid()).get_result::<i32>(conn)?;
+    diesel::update(tag::table.find(tag_id))
+      .set(tag::dateModified.eq(chrono::Utc::now().naive_utc()))
+      .execute(conn)?;
+    Ok(tag_alias::table.find(alias_id).first(conn)?)
+  })
+}
+
+/// Removes a non-primary alias from its tag. Switch the primary alias first if you want to remove it.
+pub fn remove_tag_alias(state: &DbState, alias_id: i32) -> Result<usize, DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let alias = tag_alias::table
+      .find(alias_id)
+      .first::<TagAlias>(conn)
+      .or_not_found("tag alias", alias_id)?;
+    if let Some(tag_id) = alias.tag_id {
+      let t = tag::table
+        .find(tag_id)
+        .first::<Tag>(conn)
+        .or_not_found("tag", tag_id)?;
+      if t.primary_alias_id == Some(alias_id) {
+        return Err(DbErrors::ConstraintViolation(format!(
+          "alias {} is the primary alias of tag {}",
+          alias_id, tag_id
+        )));
+      }
+      diesel::update(tag::table.find(tag_id))
+        .set(tag::dateModified.eq(chrono::Utc::now().naive_utc()))
+        .execute(conn)?;
+    }
+    Ok(diesel::delete(tag_alias::table.find(alias_id)).execute(conn)?)
+  })
+}
+
+/// Makes an existing alias of the tag its primary alias.
+pub fn set_primary_alias(
+  state: &DbState,
+  tag_id: i32,
+  alias_id: i32,
+) -> Result<(Tag, Vec<TagAlias>), DbErrors> {
+  let conn = &mut *state.write()?;
+  conn.transaction(|conn| {
+    let alias = tag_alias::table
+      .find(alias_id)
+      .first::<TagAlias>(conn)
+      .or_not_found("tag alias", alias_id)?;
+    if alias.tag_id != Some(tag_id) {
+      return Err(DbErrors::ConstraintViolation(format!(
+        "alias {} does not belong to tag {}",
+        alias_id, tag_id
+      )));
+    }
+    diesel::update(tag::table.find(tag_id))
+      .set(tag::primaryAliasId.eq(alias_id))
+      .execute(conn)?;
+    touch_tag(conn, tag_id)?;
+    load_tag(conn, tag_id)
+  })
+}
+
+fn load_tag(conn: &mut SqliteConnection, tag_id: i32) -> Result<(Tag, Vec<TagAlias>), DbErrors> {
+  let tag_obj = tag::table
+    .find(tag_id)
+    .first::<Tag>(conn)
+    .or_not_found("tag", tag_id)?;
+  let aliases = tag_alias::table
+    .filter(tag_alias::tagId.eq(tag_id))
+    .load::<TagAlias>(conn)?;
+  Ok((tag_obj, aliases))
+}
+
+/// Bumps the tag's modified date and refreshes `tagsStr` on every game that uses it.
+fn touch_tag(conn: &mut SqliteConnection, tag_id: i32) -> Result<(), DbErrors> {
+  diesel::update(tag::table.find(tag_id))
+    .set(tag::dateModified.eq(chrono::Utc::now().naive_utc()))
+    .execute(conn)?;
+  let game_ids = game_tags_tag::table
+    .filter(game_tags_tag::tagId.eq(tag_id))
+    .select(game_tags_tag::gameId)
+    .load::<String>(conn)?;
+  rebuild_tags_str(conn, &game_ids)
+}
 
 // find_primary_aliases
 
 // broadcast_tag_categories
 
-// get_filter_ids_query (?)
+/// Builds a subquery selecting the IDs of every game tagged with any of the given tags.
+/// Tags are matched by any of their aliases, case-insensitively.
+pub(crate) fn get_filter_ids_query<'a>(
+  tag_names: Vec<String>,
+) -> game_tags_tag::BoxedQuery<'a, Sqlite, Text> {
+  game_tags_tag::table
+    .select(game_tags_tag::gameId)
+    .filter(
+      game_tags_tag::tagId.nullable().eq_any(
+        tag_alias::table
+          .filter(tag_alias::name.eq_any(tag_names))
+          .select(tag_alias::tagId),
+      ),
+    )
+    .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::{find_game, save_game};
+  use crate::{test_game, test_state};
+
+  fn tag_game(state: &DbState, game_id: &str, tag_id: i32) {
+    diesel::insert_into(game_tags_tag::table)
+      .values((
+        game_tags_tag::gameId.eq(game_id),
+        game_tags_tag::tagId.eq(tag_id),
+      ))
+      .execute(&mut *state.write().unwrap())
+      .unwrap();
+    rebuild_tags_str(&mut state.write().unwrap(), &[game_id.to_string()]).unwrap();
+  }
+
+  fn new_tag(state: &DbState, name: &str) -> (Tag, Vec<TagAlias>) {
+    create_tag(
+      state,
+      InsertableTag {
+        name: name.to_string(),
+        category_id: None,
+        description: None,
+      },
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn create_tag_with_primary_alias() {
+    let state = test_state();
+    let (t, aliases) = new_tag(&state, "Puzzle");
+    assert_eq!(aliases.len(), 1);
+    assert_eq!(t.primary_alias_id, Some(aliases[0].id));
+    assert_eq!(aliases[0].name, "Puzzle");
+  }
+
+  #[test]
+  fn switch_primary_alias_updates_games() {
+    let state = test_state();
+    save_game(&state, test_game("g1", "Game One")).unwrap();
+    let (t, _) = new_tag(&state, "Puzzle");
+    tag_game(&state, "g1", t.id);
+    let alias = add_alias_to_tag(&state, t.id, "Puzzler".to_string()).unwrap();
+
+    assert!(remove_tag_alias(&state, t.primary_alias_id.unwrap()).is_err());
+    let (t, _) = set_primary_alias(&state, t.id, alias.id).unwrap();
+    assert_eq!(t.primary_alias_id, Some(alias.id));
+    assert_eq!(
+      find_game(&state, "g1".to_string()).unwrap().tags_str,
+      "Puzzler"
+    );
+  }
+
+  #[test]
+  fn save_tag_category_only_changes_one_row() {
+    let state = test_state();
+    let category = |name: &str| InsertableTagCategory {
+      name: name.to_string(),
+      color: "#fff".to_string(),
+      description: None,
+    };
+    let first = create_tag_category(&state, category("First")).unwrap();
+    let second = create_tag_category(&state, category("Second")).unwrap();
+
+    let saved = save_tag_category(&state, first.id, category("Renamed")).unwrap();
+    assert_eq!(saved.name, "Renamed");
+    assert_eq!(get_tag_category(&state, second.id).unwrap().name, "Second");
+    assert!(matches!(
+      save_tag_category(&state, second.id + 100, category("Nope")),
+      Err(DbErrors::NotFound { .. })
+    ));
+  }
+
+  #[test]
+  fn tags_str_is_sorted_by_name() {
+    let state = test_state();
+    save_game(&state, test_game("g1", "Game One")).unwrap();
+    save_game(&state, test_game("g2", "Game Two")).unwrap();
+    let (z, _) = new_tag(&state, "Zany");
+    let (a, _) = new_tag(&state, "action");
+    tag_game(&state, "g1", z.id);
+    tag_game(&state, "g1", a.id);
+    rebuild_tags_str(&mut state.write().unwrap(), &["g2".to_string()]).unwrap();
+    assert_eq!(
+      find_game(&state, "g1".to_string()).unwrap().tags_str,
+      "action; Zany"
+    );
+    assert_eq!(find_game(&state, "g2".to_string()).unwrap().tags_str, "");
+  }
+
+  #[test]
+  fn merge_tags_repoints_games() {
+    let state = test_state();
+    save_game(&state, test_game("g1", "Game One")).unwrap();
+    save_game(&state, test_game("g2", "Game Two")).unwrap();
+    let (src, _) = new_tag(&state, "Puzzles");
+    let (dst, _) = new_tag(&state, "Puzzle");
+    tag_game(&state, "g1", src.id);
+    tag_game(&state, "g1", dst.id);
+    tag_game(&state, "g2", src.id);
+
+    let summary = merge_tags(&state, src.id, dst.id, true).unwrap();
+    assert_eq!(summary.games_affected, 2);
+    assert_eq!(summary.aliases_moved, 1);
+    assert_eq!(summary.aliases_deleted, 0);
+
+    let (_, aliases) = get_tag_by_id(&state, dst.id).unwrap();
+    assert_eq!(aliases.len(), 2);
+    assert!(get_tag_by_id(&state, src.id).is_err());
+    assert_eq!(
+      game_tags_tag::table
+        .count()
+        .get_result::<i64>(&mut *state.write().unwrap())
+        .unwrap(),
+      2
+    );
+    assert_eq!(
+      find_game(&state, "g1".to_string()).unwrap().tags_str,
+      "Puzzle"
+    );
+    assert_eq!(
+      find_game(&state, "g2".to_string()).unwrap().tags_str,
+      "Puzzle"
+    );
+  }
+
+  #[test]
+  fn delete_tag_cleans_up() {
+    let state = test_state();
+    save_game(&state, test_game("g1", "Game One")).unwrap();
+    let (a, _) = new_tag(&state, "Action");
+    let (p, _) = new_tag(&state, "Puzzle");
+    tag_game(&state, "g1", a.id);
+    tag_game(&state, "g1", p.id);
+    assert_eq!(
+      find_game(&state, "g1".to_string()).unwrap().tags_str,
+      "Action; Puzzle"
+    );
+
+    delete_tag(&state, a.id).unwrap();
+    assert_eq!(
+      find_game(&state, "g1".to_string()).unwrap().tags_str,
+      "Puzzle"
+    );
+    assert_eq!(
+      tag_alias::table
+        .count()
+        .get_result::<i64>(&mut *state.write().unwrap())
+        .unwrap(),
+      1
+    );
+    assert_eq!(
+      game_tags_tag::table
+        .count()
+        .get_result::<i64>(&mut *state.write().unwrap())
+        .unwrap(),
+      1
+    );
+  }
+
+  #[test]
+  fn tag_suggestions_ranking() {
+    let state = test_state();
+    new_tag(&state, "Puzzle");
+    let (jigsaw, _) = new_tag(&state, "Jigsaw");
+    add_alias_to_tag(&state, jigsaw.id, "Puzzle Pieces".to_string()).unwrap();
+    let (sliding, _) = new_tag(&state, "Sliding Puzzle");
+    new_tag(&state, "Puzzle Gore");
+    for id in ["a", "b"] {
+      save_game(&state, test_game(id, id)).unwrap();
+    }
+    tag_game(&state, "a", sliding.id);
+    tag_game(&state, "b", sliding.id);
+    tag_game(&state, "a", jigsaw.id);
+
+    let names = |state: &DbState| -> Vec<String> {
+      find_tag_suggestions(state, "puzzle".to_string(), 10)
+        .unwrap()
+        .into_iter()
+        .map(|s| s.primary_alias)
+        .collect()
+    };
+    assert_eq!(
+      names(&state),
+      vec!["Puzzle", "Jigsaw", "Puzzle Gore", "Sliding Puzzle"]
+    );
+    let top = find_tag_suggestions(&state, "PUZZLE P".to_string(), 1).unwrap();
+    assert_eq!(top[0].tag_id, jigsaw.id);
+    assert_eq!(top[0].matched_alias, "Puzzle Pieces");
+    assert_eq!(top[0].games_count, 1);
+
+    state.exclusions.write().unwrap().tags = vec!["Puzzle Gore".to_string()];
+    assert_eq!(names(&state), vec!["Puzzle", "Jigsaw", "Sliding Puzzle"]);
+    assert!(find_tag_suggestions(&state, "%".to_string(), 10)
+      .unwrap()
+      .is_empty());
+  }
+}
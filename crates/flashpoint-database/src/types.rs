@@ -1,11 +1,40 @@
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::Duration;
 
-use crate::models::GameRelation;
+use crate::models::{AdditionalApp, Game, GameRelation};
 
 #[derive(Debug)]
 pub enum DbErrors {
   Connection(diesel::ConnectionError),
+  /// No pooled connection became free in time.
+  Pool(diesel::r2d2::PoolError),
   ReadFailed,
+  /// No `entity` row exists with the given key.
+  NotFound {
+    entity: &'static str,
+    key: String,
+  },
+  /// The change would break a constraint, e.g. a duplicate or a missing parent row.
+  ConstraintViolation(String),
+  /// The request itself is invalid, e.g. a cursor for a different order.
+  InvalidArgument(String),
+  /// A row points at another row that doesn't exist, e.g. a tag without a primary alias.
+  BrokenReference {
+    entity: &'static str,
+    key: String,
+    missing: &'static str,
+  },
+  Query(diesel::result::Error),
+  /// The database was migrated by a newer version of the backend.
+  UnknownSchemaVersion {
+    found: String,
+    latest: &'static str,
+  },
+  /// The database has tables, but neither our migration history nor the legacy launcher's.
+  UnrecognizedSchema(String),
 }
 
 impl std::fmt::Display for DbErrors {
@@ -14,29 +43,220 @@ impl std::fmt::Display for DbErrors {
       DbErrors::Connection(e) => {
         write!(f, "database connection error: {}", e)
       }
+      DbErrors::Pool(e) => {
+        write!(f, "database pool error: {}", e)
+      }
       DbErrors::ReadFailed => {
         write!(f, "database read failure")
       }
+      DbErrors::NotFound { entity, key } => {
+        write!(f, "{} {} not found", entity, key)
+      }
+      DbErrors::ConstraintViolation(msg) => {
+        write!(f, "constraint violation: {}", msg)
+      }
+      DbErrors::InvalidArgument(msg) => {
+        write!(f, "invalid argument: {}", msg)
+      }
+      DbErrors::BrokenReference {
+        entity,
+        key,
+        missing,
+      } => {
+        write!(f, "{} {} has no {}", entity, key, missing)
+      }
+      DbErrors::Query(e) => {
+        write!(f, "database query error: {}", e)
+      }
+      DbErrors::UnknownSchemaVersion { found, latest } => {
+        write!(
+          f,
+          "database schema version {} is unknown, the latest supported is {}. Was it opened by a \
+           newer version?",
+          found, latest
+        )
+      }
+      DbErrors::UnrecognizedSchema(msg) => {
+        write!(f, "unrecognized database schema: {}", msg)
+      }
+    }
+  }
+}
+
+impl From<diesel::result::Error> for DbErrors {
+  fn from(e: diesel::result::Error) -> Self {
+    use diesel::result::{DatabaseErrorKind, Error};
+    match e {
+      Error::DatabaseError(
+        DatabaseErrorKind::UniqueViolation
+        | DatabaseErrorKind::ForeignKeyViolation
+        | DatabaseErrorKind::NotNullViolation
+        | DatabaseErrorKind::CheckViolation,
+        info,
+      ) => DbErrors::ConstraintViolation(info.message().to_string()),
+      e => DbErrors::Query(e),
     }
   }
 }
 
+/// Turns diesel's key-less `NotFound` into `DbErrors::NotFound` for a specific row.
+pub(crate) trait OrNotFound<T> {
+  fn or_not_found(self, entity: &'static str, key: impl ToString) -> Result<T, DbErrors>;
+}
+
+impl<T> OrNotFound<T> for Result<T, diesel::result::Error> {
+  fn or_not_found(self, entity: &'static str, key: impl ToString) -> Result<T, DbErrors> {
+    self.map_err(|e| match e {
+      diesel::result::Error::NotFound => DbErrors::NotFound {
+        entity,
+        key: key.to_string(),
+      },
+      e => e.into(),
+    })
+  }
+}
+
 impl std::error::Error for DbErrors {}
 
-/// An opaque structure that holds the current database state.
+pub(crate) type ReadConnection = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+/// An opaque structure that holds the current database state. It can be shared between threads:
+/// reads run in parallel on pooled connections, while writes take turns on a single connection.
 pub struct DbState {
-  pub(crate) conn: SqliteConnection,
+  pub(crate) readers: Pool<ConnectionManager<SqliteConnection>>,
+  pub(crate) writer: Mutex<SqliteConnection>,
+  pub(crate) exclusions: RwLock<GameExclusions>,
+}
+
+impl DbState {
+  /// Takes a pooled connection for queries that don't change anything.
+  pub(crate) fn read(&self) -> Result<ReadConnection, DbErrors> {
+    self.readers.get().map_err(DbErrors::Pool)
+  }
+
+  /// Waits for the write connection. Hold it for as little as possible, every other write is
+  /// blocked until it's dropped.
+  pub(crate) fn write(&self) -> Result<MutexGuard<'_, SqliteConnection>, DbErrors> {
+    // A panic mid-write leaves nothing half done, the transaction was rolled back on unwind.
+    Ok(self.writer.lock().unwrap_or_else(|e| e.into_inner()))
+  }
+
+  pub(crate) fn exclusions(&self) -> RwLockReadGuard<'_, GameExclusions> {
+    self.exclusions.read().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+/// How the database connections are set up.
+#[derive(Clone, Debug)]
+pub struct DbOptions {
+  /// Maximum number of connections used for reads.
+  pub pool_size: u32,
+  /// How long a connection waits on a lock held by another connection before giving up.
+  pub busy_timeout: Duration,
+}
+
+impl Default for DbOptions {
+  fn default() -> Self {
+    Self {
+      pool_size: 4,
+      busy_timeout: Duration::from_secs(5),
+    }
+  }
+}
+
+/// Games hidden from every query, resolved from the user's preferences by `filter::set_game_filters`.
+#[derive(Default, Clone, Debug)]
+pub struct GameExclusions {
+  /// Tag names (any alias) hidden by the active tag filter groups.
+  pub tags: Vec<String>,
+  /// Tag categories whose tags are all hidden by the active tag filter groups.
+  pub categories: Vec<String>,
+  pub hide_extreme: bool,
+  pub hide_broken: bool,
+  /// Whether the tag filter groups also apply to curation queries.
+  pub tag_filters_in_curate: bool,
+  /// Libraries never picked from by `find_random_games`.
+  pub excluded_random_libraries: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", default)]
 pub struct ParsedSearch {
   pub generic_blacklist: Vec<String>,
   pub generic_whitelist: Vec<String>,
   pub blacklist: Vec<GameRelation>,
   pub whitelist: Vec<GameRelation>,
+  /// Tag names (any alias) that a game must have.
+  pub tag_whitelist: Vec<String>,
+  /// Tag names (any alias) that a game must not have.
+  pub tag_blacklist: Vec<String>,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct FilterOpts {
+  /// The page size used by `search_games`.
   pub search_limit: Option<i64>,
   pub playlist_id: Option<String>,
   pub search_query: Option<ParsedSearch>,
+  /// Set when searching for curation. Tag filter groups are then only applied if
+  /// `tag_filters_in_curate` is enabled.
+  pub curate: bool,
+}
+
+/// Narrows down `find_random_games`. Unset fields pick from every game.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RandomGameOpts {
+  pub count: usize,
+  pub library: Option<String>,
+  pub search_query: Option<ParsedSearch>,
+}
+
+/// Where the next page of a search starts. `key` holds the ordering column's value from the last row
+/// returned, and `skip` counts the rows with that same value that were already returned.
+/// Without a key (playlist order), `skip` is a plain offset.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameCursor {
+  pub key: Option<GameRelation>,
+  pub skip: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GamePage {
+  pub games: Vec<Game>,
+  /// None once the last page has been returned.
+  pub next_cursor: Option<GameCursor>,
+  /// The number of games matching the filters, across all pages.
+  pub total: i64,
+}
+
+/// What launching an additional app does, based on its application path.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum AddAppKind {
+  /// `:message:` - shows the launch command to the user as a message.
+  Message { text: String },
+  /// `:extras:` - opens the folder named by the launch command inside the Extras folder.
+  Extras { folder: String },
+  /// Anything else - runs the application path with the launch command as its arguments.
+  Executable { path: String, args: String },
+}
+
+impl AdditionalApp {
+  pub fn kind(&self) -> AddAppKind {
+    match self.application_path.as_str() {
+      ":message:" => AddAppKind::Message {
+        text: self.launch_command.clone(),
+      },
+      ":extras:" => AddAppKind::Extras {
+        folder: self.launch_command.clone(),
+      },
+      path => AddAppKind::Executable {
+        path: path.to_string(),
+        args: self.launch_command.clone(),
+      },
+    }
+  }
 }
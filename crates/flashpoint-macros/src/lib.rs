@@ -12,7 +12,7 @@ use syn::{
   FieldsUnnamed, Ident, Variant,
 };
 /// Makes a relation enum out of the struct fields, and generates filtering functions for them.
-#[proc_macro_derive(TableQueryBuilder)]
+#[proc_macro_derive(TableQueryBuilder, attributes(fp_table_query_builder))]
 pub fn table_query_builder_macro(input: TokenStream) -> TokenStream {
   // Parse the input tokens into a syntax tree
   let input = parse_macro_input!(input as DeriveInput);
@@ -79,6 +79,11 @@ pub fn table_query_builder_macro(input: TokenStream) -> TokenStream {
   // These two are for the bodies of the match statements in page().
   let mut ascending_page = proc_macro2::TokenStream::new();
   let mut descending_page = proc_macro2::TokenStream::new();
+  // These two are for the bodies of the match statements in from_column_name() and with_value_from().
+  let mut names_to_variants = proc_macro2::TokenStream::new();
+  let mut values_from_row = proc_macro2::TokenStream::new();
+  // The struct we're deriving for, so with_value_from() can take a row of it.
+  let struct_ident = input.ident.clone();
 
   // Loop over the struct's fields.
   for field in struct_fields {
@@ -158,7 +163,7 @@ pub fn table_query_builder_macro(input: TokenStream) -> TokenStream {
         )
       }
       // If it's an Option<T>, handle it specially. The Some() arm should be normal, the None arm should be IS (NOT) NULL.
-      opt if matches!(get_option_inner(&type_str), Some(_)) => {
+      opt if get_option_inner(&type_str).is_some() => {
         match get_option_inner(opt)
           .expect("We just checked that this was safe!?!")
           .as_str()
@@ -235,6 +240,21 @@ pub fn table_query_builder_macro(input: TokenStream) -> TokenStream {
             #enum_ident::#field_ident(s) => q.filter(#table_name_ident::#column_ident.le(s.clone()))
             .order((#table_name_ident::#column_ident.desc(), #table_name_ident::#unique_column_ident.desc())),
         });
+
+    // Accept both the column name and the field name, without repeating a pattern if they're the same.
+    let field_name = field_ident.to_string();
+    let column_name = column_ident.to_string();
+    let name_pattern = if field_name == column_name {
+      quote! { #field_name }
+    } else {
+      quote! { #column_name | #field_name }
+    };
+    names_to_variants.append_all(quote! {
+            #name_pattern => Some(#enum_ident::#field_ident(Default::default())),
+        });
+    values_from_row.append_all(quote! {
+            #enum_ident::#field_ident(_) => #enum_ident::#field_ident(row.#field_ident.clone()),
+        });
   }
 
   let vis = input.vis;
@@ -315,6 +335,23 @@ pub fn table_query_builder_macro(input: TokenStream) -> TokenStream {
             };
             k.offset(1).limit(page_size)
           }
+
+          /// Looks up a relation by its column name or field name. The relation holds a default value,
+          /// so it's only useful for picking a column, e.g. for ordering.
+          #vis fn from_column_name(name: &str) -> Option<Self> {
+            match name {
+              #names_to_variants
+              _ => None,
+            }
+          }
+
+          /// Returns the same relation, holding the value of that column in the given row.
+          /// Useful for building a keyset pagination cursor out of the last row of a page.
+          #vis fn with_value_from(&self, row: &#struct_ident) -> Self {
+            match self {
+              #values_from_row
+            }
+          }
       }
   };
 
@@ -397,15 +434,9 @@ fn get_attr_value(attr: &Attribute, expected_wrapper: &str, expected_key: &str)
 fn get_option_inner(type_str: &str) -> Option<String> {
   let no_option = type_str.strip_prefix("Option")?.trim();
   let no_brackets = no_option.strip_prefix("<")?.strip_suffix(">")?.trim();
-  return Some(no_brackets.to_string());
+  Some(no_brackets.to_string())
 }
 
 fn truthiness(s: &str) -> bool {
-  match s.clone().to_lowercase().as_str() {
-    "true" => true,
-    "t" => true,
-    "yes" => true,
-    "y" => true,
-    _ => false,
-  }
+  matches!(s.to_lowercase().as_str(), "true" | "t" | "yes" | "y")
 }